@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::app::config::{
+    model::Config,
+    sort::{Sort, TimeWindow},
+};
+
+#[derive(Parser, Debug)]
+#[command(name = "ridit", version, about = "Reddit Image Downloader CLI")]
+pub struct Cli {
+    /// Path to the config file. Defaults to ridit.toml in the current directory, then the user config directory.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    /// Defaults to `download` when no command is given.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Download images from the configured subreddits.
+    Download,
+    /// Keep running and download on the interval configured in [run].
+    Watch,
+    /// Create a config file with default values.
+    Init {
+        /// Overwrite the config file if it already exists.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Inspect the config.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Check the config for errors.
+    Validate,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    /// Print the config used for this run, with command line overrides applied.
+    Show,
+    /// Print the location of the config file.
+    Path,
+}
+
+/// Config values overridden for a single run.
+#[derive(Args, Debug, Clone, Default)]
+pub struct Overrides {
+    /// Subreddit to download from instead of the configured subreddits and sources. Can be repeated.
+    #[arg(long = "subreddit", short = 's', global = true, value_name = "NAME")]
+    pub subreddits: Vec<String>,
+
+    /// Listing sort: hot, new, rising, controversial or top.
+    #[arg(long, global = true)]
+    pub sort: Option<Sort>,
+
+    /// Time window for top and controversial sorts: hour, day, week, month, year or all.
+    #[arg(long, global = true)]
+    pub time_window: Option<TimeWindow>,
+
+    /// Download location.
+    #[arg(long, global = true)]
+    pub path: Option<String>,
+
+    /// Do not create symbolic links.
+    #[arg(long, global = true)]
+    pub no_symlink: bool,
+
+    /// Log level: error, warn, info, debug, trace or off.
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+}
+
+impl Overrides {
+    pub fn apply(&self, c: &mut Config) {
+        if !self.subreddits.is_empty() {
+            c.downloads.subreddits = self.subreddits.clone();
+            // keep the overrides of the requested subreddits
+            c.subreddit.retain(|sub| {
+                self.subreddits
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(sub.name.as_str()))
+            });
+            c.source.clear();
+        }
+        if let Some(sort) = self.sort {
+            c.downloads.sort = sort;
+            c.subreddit.iter_mut().for_each(|sub| sub.sort = None);
+            c.source.iter_mut().for_each(|source| source.sort = None);
+        }
+        if let Some(time_window) = self.time_window {
+            c.downloads.time_window = time_window;
+            c.subreddit
+                .iter_mut()
+                .for_each(|sub| sub.time_window = None);
+            c.source
+                .iter_mut()
+                .for_each(|source| source.time_window = None);
+        }
+        if let Some(path) = self.path.as_ref() {
+            c.downloads.path = path.clone();
+        }
+        if let Some(level) = self.log_level.as_ref() {
+            c.log.level = level.clone();
+        }
+        if self.no_symlink {
+            c.symbolic_link.enable = false;
+            c.subreddit
+                .iter_mut()
+                .for_each(|sub| sub.symbolic_link = None);
+            c.source
+                .iter_mut()
+                .for_each(|source| source.symbolic_link = None);
+        }
+    }
+}
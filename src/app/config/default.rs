@@ -2,14 +2,22 @@ use anyhow::{Context, Error, Result};
 use home;
 use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-static DEFAULT_CONFIG: &'static str = r##"
+pub(crate) static DEFAULT_CONFIG: &str = r##"
 [run]
 # Prevent closing the cli window on task done.
 # Set to off if you run this application via scripting.
 hold_on_job_done = true
 
+# Keep running and download again every `interval` seconds. Useful when running this app as a service.
+# `hold_on_job_done` is ignored when daemon is enabled. Stop the app with Ctrl+C or SIGTERM.
+# It can also be enabled for a single run with `ridit watch`.
+daemon = false
+interval = 3600
+# Adds a random delay between 0 and `jitter` seconds to every interval, so the app does not call reddit at the exact same time every run.
+jitter = 300
+
 [downloads]
 # The subreddits to subscribe to
 subreddits = ["wallpaper", "wallpapers"]
@@ -20,6 +28,10 @@ nsfw = true
 # sort. valid values: "hot", "new", "top", "controversial", "rising" (case insensitive). Default or Invalid values are treated as "hot"
 sort = "hot"
 
+# Time window for "top" and "controversial" sorts. Ignored for other sorts.
+# valid values: "hour", "day", "week", "month", "year", "all" (case insensitive). Default or Invalid values are treated as "day"
+time_window = "day"
+
 # Download location. Defaults to download folder relative to where the app is running from. (it uses its cwd value for relative paths)
 # Windows user haves to use double backslash to write the path value or else the app will throw an error.
 # If say download location is `C:\wallpapers\ridit`, Then it must be written like this: `C:\\wallpapers\\ridit` 
@@ -34,6 +46,55 @@ download_timeout = 30000
 # The app checks if file already exist. If it does, it will skip the download of the image. Set to true to force redownloading
 proceed_download_on_file_exist = false
 
+# What to do when the file name is already taken by the image of another post, e.g. crossposts or links with the same name.
+# Also applies to symbolic links. Telling files of other posts apart from earlier runs needs [history] enabled.
+# valid values:
+#   "skip"           keep the existing file and skip the image.
+#   "overwrite"      replace the existing file.
+#   "suffix"         save as `name_1.jpg`, `name_2.jpg`, ...
+#   "prefix-with-id" save as `<post id>_name.jpg`. Falls back to "suffix" when that's taken as well.
+on_collision = "suffix"
+
+# Where the file extension comes from. valid values: "content", "url".
+# "content" names the file after the downloaded image's real format, e.g. a `.jpg` link that serves webp is saved as `.webp`,
+# and links without extension get one. "url" keeps the name from the link as is.
+extension = "content"
+
+# Folders inside `path` the images are saved to. Use `/` to nest folders. Empty value saves every image directly in `path`.
+# Placeholders:
+#   every filename_template placeholder below except {name} and {ext},
+#   {year}, {month}, {day} (post date),
+#   {orientation} (landscape, portrait or square),
+#   {resolution_bucket} (by the longer side: sd, 720p, 1080p, 1440p, 4k, 5k or 8k).
+#   orientation and resolution_bucket are `unknown` for images saved without preview, see [verify_dimensions].
+# Examples: "{subreddit}/{year}/{month}", "{orientation}/{subreddit}", "{resolution_bucket}"
+directory_template = "{subreddit}"
+
+# Name of the saved files. Empty value keeps the name from the image link, e.g. `a8f3kq2.jpg`.
+# Placeholders:
+#   {subreddit}, {source} (name of the [[source]] the post was listed from, the subreddit otherwise),
+#   {id} (post id), {title}, {author}, {width}, {height},
+#   {created} (post date, `{created:%Y-%m-%d}` by default, any strftime format works, e.g. `{created:%Y%m%d_%H%M}`),
+#   {name} (name from the image link without extension), {ext} (extension, see `extension` above),
+#   {index} (position in a gallery post. Appended automatically to gallery items when not used).
+# Example: "{created:%Y-%m-%d}_{title}_{id}.{ext}"
+filename_template = ""
+# Titles longer than this many characters are cut off.
+title_max_length = 100
+
+# Domains of the posts' links to download from, e.g. ["i.redd.it", "imgur.com"]. Subdomains are included, so "imgur.com" also allows "i.imgur.com".
+# Empty list allows every domain.
+allowed_domains = []
+# Domains of the posts' links to never download from. Takes precedence over `allowed_domains`.
+blocked_domains = []
+
+# Reddit returns at most 100 posts per request. The app keeps requesting the next page until it has fetched `pages` pages,
+# has seen `max_posts` posts, or the subreddit runs out of posts.
+# Filters are applied after fetching, so more pages usually means more images to download.
+pages = 1
+# Set to 0 to only limit by `pages`.
+max_posts = 0
+
 [aspect_ratio]
 enable = true
 height_aspect = 9
@@ -49,11 +110,77 @@ width_aspect = 16
 # higher value of ratio_range means more valid images, but if wallpaper is set to `stretched` on your desktop, some of those images may look bad
 ratio_range = 0.5
 
+# To accept more than one aspect ratio, list them in `ratios`. It replaces `width_aspect` and `height_aspect` when not empty.
+# Each entry has a `ratio`, like "16:9" or "1.78", or one of the presets:
+#   "landscape" wider than tall, "portrait" taller than wide, "square" width about the same as height.
+# `tolerance` is the accepted distance from the ratio, `ratio_range` above by default.
+# `min` and `max` set the lowest and highest accepted ratio instead, for uneven bounds.
+#
+# ratios = [
+#     { ratio = "16:9" },
+#     { ratio = "16:10", tolerance = 0.05 },
+#     { ratio = "21:9", min = 2.3, max = 2.5 },
+#     { ratio = "32:9", tolerance = 0.2 },
+# ]
+
 [minimum_size]
 enable = true
 minimum_height = 1080
 minimum_width = 1920
 
+# Images larger than this are skipped, e.g. huge scans no screen can show. 0 means no limit for that side.
+[maximum_size]
+enable = false
+maximum_height = 4320
+maximum_width = 7680
+
+# Limits of the image file size in bytes. 0 means no limit.
+# The size the server reports is checked before downloading, and downloads stop as soon as they go over `maximum_bytes`.
+[file_size]
+enable = false
+minimum_bytes = 0
+# 50 MB
+maximum_bytes = 52428800
+
+# Filters on the posts. Values that are commented out or empty don't filter.
+[filters]
+# Pinned announcement and meta posts.
+skip_stickied = true
+# min_score = 10
+# Between 0.0 and 1.0.
+# min_upvote_ratio = 0.8
+# Posts older than this are skipped. Useful with `sort = "new"`.
+# max_age_hours = 168
+# Link flairs, compared ignoring case. When `required_flairs` is not empty, posts without one of these flairs are skipped.
+required_flairs = []
+excluded_flairs = []
+# Regular expressions matched against the post title, e.g. "(?i)\\bOC\\b". Add `(?i)` to ignore case.
+# When `title_include` is not empty, the title must match at least one of them. Titles matching any of `title_exclude` are skipped.
+title_include = []
+title_exclude = []
+# Usernames without `u/`, compared ignoring case. When `allowed_authors` is not empty, only their posts are downloaded.
+allowed_authors = []
+blocked_authors = []
+
+# Reddit's preview metadata is used to check aspect ratio and minimum size before downloading, but it's sometimes wrong or missing.
+# When enabled, the real dimensions are read from the downloaded file (png, jpeg, webp and gif),
+# and files that fail the aspect ratio or minimum size check are deleted.
+[verify_dimensions]
+enable = false
+# Posts without preview are skipped by default. Set to true to download them and check them after download instead.
+# Only works when `enable` is true, and only for links that end with an image extension.
+download_without_preview = false
+
+# Rejects downloads that are not images, like html error pages, or links to pages instead of images.
+# The Content-Type header and the first bytes of the file are checked.
+[validation]
+enable = true
+# Images that hosts serve in place of removed images. Files with the same size and sha256 hash are deleted.
+# Images that redirect to imgur's removed.png are always rejected, so imgur needs no entry here.
+# Set `any_content = true` instead of `sha256` to delete every file of that size. Real images of that size are deleted too.
+# Example: [{ size = 503, sha256 = "<hex encoded sha256>" }]
+placeholders = []
+
 # Symbolic Link in this app means the app can make an image appear in two different folder but without copying the file.
 # It's like a shortcut to a file or program but the os and other programs treats symbolic link like actual file.
 # The main use for symbolic link in this app is to gather images from different subreddit folders into one folder without copying the actual file.
@@ -67,14 +194,114 @@ use_custom_path = false
 # FYI, windows support forward slash as handling subfolder
 custom_path = "downloads/_join"
 
+# History remembers every downloaded image, so images you delete or move are not downloaded again.
+[history]
+enable = true
+# Location of the history database. Empty value means `.ridit_history.sqlite` inside the download path.
+path = ""
+
+# Dedup finds images with the same content, e.g. the same wallpaper posted on several subreddits, by their sha256 hash.
+# Images already in the download path are hashed on the first run. Needs [history] enabled.
+[dedup]
+enable = false
+# What happens to the later copies. valid values:
+#   "hardlink" replace the copy with a hard link to the first one. Takes no extra space, and every folder still has the image.
+#   "symlink"  replace the copy with a symbolic link to the first one.
+#   "skip"     delete the copy.
+# Copies are not added to the symbolic link folder either way.
+action = "hardlink"
+
+# Perceptual hash finds reposts of the same image that were resized or re-encoded, which dedup can't.
+# Images are compared after download. Of similar images, the one with the most pixels is kept and the others are removed.
+# Needs [history] enabled.
+[perceptual_hash]
+enable = false
+# valid values: "ahash" (fastest, least accurate), "dhash", "phash" (slowest, survives more edits)
+algorithm = "dhash"
+# Images whose 64 bit hashes differ in at most this many bits are similar. Higher values find more reposts,
+# but also mistake different images with similar composition for reposts.
+threshold = 8
+
+[log]
+# valid values: "error", "warn", "info", "debug", "trace", "off"
+level = "info"
+# "text" for reading, "json" for one json object per line that log pipelines can consume.
+format = "text"
+# Also write logs to this file. Empty value means logs are only printed to the terminal.
+file = ""
+
 # Common users should have no need to change these values.
 [advanced]
 # User Agent is a way for reddit to know who is calling their services.
 user_agent = "ridit"
+# Client id of an imgur app, used to download every image of imgur albums. Register one on https://api.imgur.com/oauth2/addclient
+# Without it, only the cover image reddit shows for the album is downloaded.
+imgur_client_id = ""
+# Credentials of a reddit app, register one on https://www.reddit.com/prefs/apps
+# With them, listings are fetched from the authenticated api on oauth.reddit.com, which allows far more requests than the public one.
+# A "script" app logs in as the account that registered it with `reddit_username` and `reddit_password`.
+# Without them, the app only authenticates itself. "installed app" apps have no secret.
+# Reddit asks authenticated apps for a unique `user_agent`, e.g. "linux:ridit:0.2.1 (by /u/your_username)".
+reddit_client_id = ""
+reddit_client_secret = ""
+reddit_username = ""
+reddit_password = ""
+
+# Per subreddit overrides. Each [[subreddit]] entry downloads from the named subreddit,
+# and can override `sort`, `time_window`, `nsfw` and the [aspect_ratio], [minimum_size], [maximum_size], [filters] and [symbolic_link] sections.
+# Anything not set falls back to the values above. Subreddits in `downloads.subreddits` keep using the values above.
+# Overridden sections must be written in full.
+#
+# [[subreddit]]
+# name = "iphonewallpapers"
+# sort = "top"
+# time_window = "week"
+#
+# [subreddit.aspect_ratio]
+# enable = true
+# height_aspect = 16
+# width_aspect = 9
+# ratio_range = 0.2
+#
+# [subreddit.minimum_size]
+# enable = true
+# minimum_height = 1920
+# minimum_width = 1080
+
+# Other listings to download from. Each [[source]] entry has a `kind`:
+#   "multireddit": the posts of several subreddits in one listing. Needs `subreddits`.
+#   "search":      posts matching `query`, searched in `subreddit`. Leave `subreddit` out to search all of reddit.
+#                  Search only takes the hot, new and top sorts.
+#   "user":        posts submitted by `user`.
+#   "post":        a single post. `url` is the post's link.
+# `name` is the folder the images are saved to. When left out, it's made from the listing,
+# e.g. "EarthPorn+SkyPorn", "wallpapers search mountain", "u_someone" or "post_abc123".
+# Set `directory_template` to save them elsewhere, e.g. "{source}/{subreddit}".
+# Sources can override the same values as [[subreddit]] entries.
+#
+# [[source]]
+# kind = "multireddit"
+# name = "landscapes"
+# subreddits = ["EarthPorn", "SkyPorn"]
+#
+# [[source]]
+# kind = "search"
+# subreddit = "wallpapers"
+# query = "mountain lake"
+# sort = "top"
+# time_window = "month"
+#
+# [[source]]
+# kind = "user"
+# user = "some_photographer"
+#
+# [[source]]
+# kind = "post"
+# url = "https://www.reddit.com/r/wallpaper/comments/abc123/some_title/"
 
 "##;
 
-static FILENAME: &'static str = "ridit.toml";
+static FILENAME: &str = "ridit.toml";
 
 pub fn get_xdg_config_dir() -> Result<PathBuf> {
     let mut p = home::home_dir().ok_or(Error::msg("failed to detect user directory"))?;
@@ -105,7 +332,7 @@ pub fn get_xdg_config_path() -> Result<PathBuf> {
 
 pub fn get_relative_config_dir() -> Result<PathBuf> {
     std::env::current_dir()
-        .map_err(|err| Error::new(err))
+        .map_err(Error::new)
         .with_context(|| "failed getting current application directory")
 }
 
@@ -125,6 +352,18 @@ pub fn check_config_exists() -> (bool, bool) {
     res
 }
 
+/// Writes the default config to `p`, creating its parent directories.
+pub fn print_config_to(p: &Path) -> Result<()> {
+    if let Some(dir) = p.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory on {}", dir.display()))?;
+    }
+    let mut file =
+        File::create(p).with_context(|| format!("failed to create config on {}", p.display()))?;
+    file.write_all(DEFAULT_CONFIG.trim().as_bytes())?;
+    Ok(())
+}
+
 pub fn print_config() -> Result<PathBuf> {
     let b = DEFAULT_CONFIG.trim().as_bytes();
 
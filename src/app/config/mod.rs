@@ -1,4 +1,4 @@
-mod default;
+pub(crate) mod default;
 pub mod model;
 pub mod sort;
 pub mod thread;
@@ -6,23 +6,23 @@ pub mod thread;
 use model::Config;
 use std::error::Error;
 use std::fs;
+use std::path::{Path, PathBuf};
 use toml;
 
-pub fn read_config() -> Result<Config, Box<dyn Error>> {
-    let c: Config;
+/// Returns the config file in the application dir if it exists, otherwise the one in the global config dir.
+pub fn get_config_path() -> Result<PathBuf, Box<dyn Error>> {
     if let Ok(p) = default::get_relative_config_path() {
-        let content =
-            fs::read(p).or_else(|err| Err(format!("failed to read riddit.toml: {}", err)))?;
-        c = toml::from_slice(content.as_ref())?;
-    } else {
-        let p = default::get_xdg_config_path()?;
-        let content = fs::read(p).or_else(|err| {
-            Err(format!(
-                "failed to read riddit.toml from global config path: {}",
-                err
-            ))
-        })?;
-        c = toml::from_slice(content.as_ref())?;
+        if p.exists() {
+            return Ok(p);
+        }
     }
+    Ok(default::get_xdg_config_path()?)
+}
+
+pub fn read_config_from<P: AsRef<Path>>(p: P) -> Result<Config, Box<dyn Error>> {
+    let p = p.as_ref();
+    let content = fs::read(p)
+        .map_err(|err| format!("failed to read config from {}: {}", p.display(), err))?;
+    let c: Config = toml::from_slice(content.as_ref())?;
     Ok(c)
 }
@@ -1,22 +1,112 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use super::{default, sort::Sort};
+use crate::app::reddit::models::{filter::PostFilter, meta::DownloadMeta, template};
+
+use super::{
+    default,
+    sort::{Sort, TimeWindow},
+};
 use anyhow::{Context, Result};
 use path_absolutize::Absolutize;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
     pub run: Run,
     pub downloads: Downloads,
     pub aspect_ratio: AspectRatio,
     pub minimum_size: MinimumSize,
+    #[serde(default)]
+    pub maximum_size: MaximumSize,
+    #[serde(default)]
+    pub file_size: FileSize,
+    #[serde(default)]
+    pub filters: Filters,
     pub advanced: Advanced,
     pub symbolic_link: SymbolicLink,
+    #[serde(default)]
+    pub history: History,
+    #[serde(default)]
+    pub log: Log,
+    #[serde(default)]
+    pub verify_dimensions: VerifyDimensions,
+    #[serde(default)]
+    pub validation: Validation,
+    #[serde(default)]
+    pub dedup: Dedup,
+    #[serde(default)]
+    pub perceptual_hash: PerceptualHash,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subreddit: Vec<Subreddit>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source: Vec<Source>,
 }
 
 impl Config {
+    /// Returns every source to download from paired with the config that applies to it.
+    /// Subreddits in the flat `downloads.subreddits` list use the global config.
+    /// A `[[subreddit]]` entry takes precedence over the flat list entry of the same name. `[[source]]` entries come last.
+    pub fn source_configs(&self) -> Vec<(Source, Config)> {
+        let mut result: Vec<(Source, Config)> = self
+            .downloads
+            .subreddits
+            .iter()
+            .filter(|name| {
+                !self
+                    .subreddit
+                    .iter()
+                    .any(|sub| sub.name.eq_ignore_ascii_case(name.as_str()))
+            })
+            .map(|name| (Source::subreddit(name.as_str()), self.clone()))
+            .collect();
+        for sub in self.subreddit.iter() {
+            let source = Source::from(sub);
+            let config = self.with_overrides(&source);
+            result.push((source, config));
+        }
+        for source in self.source.iter() {
+            let mut config = self.with_overrides(source);
+            if source.directory_template.is_none() {
+                config.downloads.directory_template = "{source}".to_string();
+            }
+            result.push((source.clone(), config));
+        }
+        result
+    }
+
+    fn with_overrides(&self, source: &Source) -> Config {
+        let mut c = self.clone();
+        if let Some(sort) = source.sort {
+            c.downloads.sort = sort;
+        }
+        if let Some(time_window) = source.time_window {
+            c.downloads.time_window = time_window;
+        }
+        if let Some(nsfw) = source.nsfw {
+            c.downloads.nsfw = nsfw;
+        }
+        if let Some(directory_template) = source.directory_template.as_ref() {
+            c.downloads.directory_template = directory_template.clone();
+        }
+        if let Some(aspect_ratio) = source.aspect_ratio.as_ref() {
+            c.aspect_ratio = aspect_ratio.clone();
+        }
+        if let Some(minimum_size) = source.minimum_size.as_ref() {
+            c.minimum_size = minimum_size.clone();
+        }
+        if let Some(maximum_size) = source.maximum_size.as_ref() {
+            c.maximum_size = maximum_size.clone();
+        }
+        if let Some(filters) = source.filters.as_ref() {
+            c.filters = filters.clone();
+        }
+        if let Some(symbolic_link) = source.symbolic_link.as_ref() {
+            c.symbolic_link = symbolic_link.clone();
+        }
+        c
+    }
+
     pub fn get_download_path(&self) -> PathBuf {
         let b = shellexpand::full(self.downloads.path.as_str()).unwrap();
         PathBuf::new()
@@ -26,64 +116,899 @@ impl Config {
             .to_path_buf()
     }
 
+    pub fn get_history_path(&self) -> PathBuf {
+        if self.history.path.trim().is_empty() {
+            return self.get_download_path().join(".ridit_history.sqlite");
+        }
+        let b = shellexpand::full(self.history.path.as_str()).unwrap();
+        PathBuf::new()
+            .join(b.as_ref())
+            .absolutize()
+            .unwrap()
+            .to_path_buf()
+    }
+
+    /// Folders of the directory template are created when an image is stored in them.
     pub fn create_dirs(&self) -> Result<()> {
         let p = self.get_download_path();
-        for subreddit in self.downloads.subreddits.iter() {
-            let p = p.join(subreddit.as_str());
-            fs::create_dir_all(p.clone())
-                .with_context(|| format!("failed to create directory on {}", p.display()))?;
-        }
-        Ok(())
+        fs::create_dir_all(p.clone())
+            .with_context(|| format!("failed to create directory on {}", p.display()))
     }
 
     pub fn print_config() -> Result<PathBuf> {
         default::print_config()
     }
 
+    pub fn print_config_to<P: AsRef<Path>>(p: P) -> Result<()> {
+        default::print_config_to(p.as_ref())
+    }
+
+    /// Checks for values that would make the app misbehave. Returns every problem found, one per line.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems: Vec<String> = Vec::new();
+        let sources = self.source_configs();
+        if sources.is_empty() {
+            problems.push("no subreddit or source is configured".to_string());
+        }
+        for (source, c) in sources.iter() {
+            let name = source.name();
+            if let Err(err) = source.check() {
+                problems.push(format!("[{}] {}", name, err));
+            }
+            if c.aspect_ratio.enable
+                && c.aspect_ratio.ratios.is_empty()
+                && (c.aspect_ratio.width_aspect == 0 || c.aspect_ratio.height_aspect == 0)
+            {
+                problems.push(format!(
+                    "[{}] aspect_ratio width_aspect and height_aspect must be greater than 0",
+                    name
+                ));
+            }
+            if c.aspect_ratio.enable {
+                for ratio in c.aspect_ratio.ratios.iter() {
+                    if let Err(err) = ratio.bounds(c.aspect_ratio.ratio_range) {
+                        problems.push(format!("[{}] aspect_ratio ratios: {}", name, err));
+                    }
+                }
+            }
+            if c.aspect_ratio.enable && c.aspect_ratio.ratio_range < 0. {
+                problems.push(format!(
+                    "[{}] aspect_ratio ratio_range must not be negative",
+                    name
+                ));
+            }
+            if c.symbolic_link.enable
+                && c.symbolic_link.use_custom_path
+                && c.symbolic_link.custom_path.trim().is_empty()
+            {
+                problems.push(format!(
+                    "[{}] symbolic_link custom_path must not be empty",
+                    name
+                ));
+            }
+        }
+        if self.downloads.path.trim().is_empty() {
+            problems.push("downloads path must not be empty".to_string());
+        }
+        if self.downloads.timeout == 0 || self.downloads.download_timeout == 0 {
+            problems
+                .push("downloads timeout and download_timeout must be greater than 0".to_string());
+        }
+        let sample = DownloadMeta {
+            post_id: "abc123".to_string(),
+            subreddit_name: "wallpaper".to_string(),
+            source: "wallpaper".to_string(),
+            title: "title".to_string(),
+            author: "author".to_string(),
+            filename: "image.jpg".to_string(),
+            ..Default::default()
+        };
+        if let Err(err) = template::render_directory(
+            self.downloads.directory_template.as_str(),
+            &sample,
+            self.downloads.title_max_length,
+        ) {
+            problems.push(format!("downloads directory_template is invalid: {}", err));
+        }
+        if !self.downloads.filename_template.trim().is_empty() {
+            if let Err(err) = template::render_filename(
+                self.downloads.filename_template.as_str(),
+                &sample,
+                self.downloads.title_max_length,
+            ) {
+                problems.push(format!("downloads filename_template is invalid: {}", err));
+            }
+        }
+        if let Err(err) = PostFilter::new(&self.filters) {
+            problems.push(format!("filters are invalid: {:#}", err));
+        }
+        for sub in self.subreddit.iter() {
+            if let Some(Err(err)) = sub.filters.as_ref().map(PostFilter::new) {
+                problems.push(format!("[{}] filters are invalid: {:#}", sub.name, err));
+            }
+        }
+        for source in self.source.iter() {
+            if let Some(Err(err)) = source.filters.as_ref().map(PostFilter::new) {
+                problems.push(format!(
+                    "[{}] filters are invalid: {:#}",
+                    source.name(),
+                    err
+                ));
+            }
+            if let Some(Err(err)) = source
+                .directory_template
+                .as_ref()
+                .map(|directory_template| {
+                    template::render_directory(
+                        directory_template.as_str(),
+                        &sample,
+                        self.downloads.title_max_length,
+                    )
+                })
+            {
+                problems.push(format!(
+                    "[{}] directory_template is invalid: {}",
+                    source.name(),
+                    err
+                ));
+            }
+        }
+        if self.file_size.enable
+            && self.file_size.maximum_bytes > 0
+            && self.file_size.minimum_bytes > self.file_size.maximum_bytes
+        {
+            problems.push("file_size minimum_bytes is greater than maximum_bytes".to_string());
+        }
+        for placeholder in self.validation.placeholders.iter() {
+            if placeholder.sha256.trim().is_empty() && !placeholder.any_content {
+                problems.push(format!(
+                    "validation placeholder of {} bytes needs sha256, or any_content = true to match every file of that size",
+                    placeholder.size
+                ));
+            }
+        }
+        if self.dedup.enable && !self.history.enable {
+            problems.push("dedup needs history to be enabled".to_string());
+        }
+        if self.perceptual_hash.enable && !self.history.enable {
+            problems.push("perceptual_hash needs history to be enabled".to_string());
+        }
+        if self.perceptual_hash.threshold >= 64 {
+            problems.push(format!(
+                "perceptual_hash threshold must be less than 64, got {}",
+                self.perceptual_hash.threshold
+            ));
+        }
+        if self.advanced.user_agent.trim().is_empty() {
+            problems.push("advanced user_agent must not be empty".to_string());
+        }
+        let advanced = &self.advanced;
+        if advanced.reddit_client_id.trim().is_empty()
+            && !(advanced.reddit_client_secret.trim().is_empty()
+                && advanced.reddit_username.trim().is_empty())
+        {
+            problems.push("advanced reddit_client_id must be set to log in to reddit".to_string());
+        }
+        if advanced.reddit_username.trim().is_empty() != advanced.reddit_password.trim().is_empty()
+        {
+            problems.push(
+                "advanced reddit_username and reddit_password must be set together".to_string(),
+            );
+        }
+        if !advanced.reddit_username.trim().is_empty()
+            && advanced.reddit_client_secret.trim().is_empty()
+        {
+            problems.push(
+                "advanced reddit_client_secret must be set to log in with reddit_username"
+                    .to_string(),
+            );
+        }
+        if !problems.is_empty() {
+            return Err(anyhow::Error::msg(problems.join("\n")));
+        }
+        Ok(())
+    }
+
     pub fn check_config_exists() -> (bool, bool) {
         default::check_config_exists()
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Run {
     pub hold_on_job_done: bool,
+    #[serde(default)]
+    pub daemon: bool,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default)]
+    pub jitter: u64,
+}
+
+fn default_interval() -> u64 {
+    3600
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Downloads {
     pub subreddits: Vec<String>,
     pub sort: Sort,
+    #[serde(default)]
+    pub time_window: TimeWindow,
     pub path: String,
     pub timeout: u64,
     pub download_timeout: u64,
     pub nsfw: bool,
     pub proceed_download_on_file_exist: bool,
+    #[serde(default)]
+    pub on_collision: Collision,
+    #[serde(default)]
+    pub extension: ExtensionSource,
+    #[serde(default = "default_directory_template")]
+    pub directory_template: String,
+    #[serde(default)]
+    pub filename_template: String,
+    #[serde(default = "default_title_max_length")]
+    pub title_max_length: usize,
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    #[serde(default)]
+    pub blocked_domains: Vec<String>,
+    #[serde(default = "default_pages")]
+    pub pages: usize,
+    #[serde(default)]
+    pub max_posts: usize,
+}
+
+fn default_pages() -> usize {
+    1
+}
+
+fn default_directory_template() -> String {
+    "{subreddit}".to_string()
+}
+
+fn default_title_max_length() -> usize {
+    100
+}
+
+/// Where the extension of the saved file comes from.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtensionSource {
+    /// keep the extension in the url, even if it does not match the image.
+    Url,
+    /// use the extension of the downloaded image's format.
+    #[default]
+    Content,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// What to do when a file or symbolic link name is already taken by the image of another post.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Collision {
+    /// keep the existing file and do not download the image.
+    Skip,
+    /// replace the existing file.
+    Overwrite,
+    /// append `_1`, `_2`, ... to the name until it's free.
+    #[default]
+    Suffix,
+    /// prefix the name with the post id. Falls back to `suffix` when that's taken as well.
+    PrefixWithId,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AspectRatio {
     pub enable: bool,
+    #[serde(default)]
     pub height_aspect: usize,
+    #[serde(default)]
     pub width_aspect: usize,
+    #[serde(default)]
     pub ratio_range: f32,
+    /// replaces `width_aspect`, `height_aspect` when not empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ratios: Vec<Ratio>,
+}
+
+impl AspectRatio {
+    /// Accepted `(min, max)` ranges of width divided by height. Fails on the first invalid entry, see [`Config::validate`].
+    pub fn bounds(&self) -> std::result::Result<Vec<(f32, f32)>, String> {
+        if self.ratios.is_empty() {
+            // a zero aspect would make every ratio infinite
+            if self.width_aspect == 0 || self.height_aspect == 0 {
+                return Err("width_aspect and height_aspect must be greater than 0".to_string());
+            }
+            let ar = self.width_aspect as f32 / self.height_aspect as f32;
+            return Ok(vec![(ar - self.ratio_range, ar + self.ratio_range)]);
+        }
+        self.ratios
+            .iter()
+            .map(|ratio| ratio.bounds(self.ratio_range))
+            .collect()
+    }
+}
+
+/// One accepted aspect ratio.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Ratio {
+    /// `16:9`, `1.78`, or one of the presets `landscape`, `portrait` and `square`.
+    pub ratio: String,
+    /// accepted distance from the ratio. Defaults to the section's `ratio_range`, or a value fitting the preset.
+    pub tolerance: Option<f32>,
+    /// lowest accepted ratio. Takes precedence over `tolerance`.
+    pub min: Option<f32>,
+    /// highest accepted ratio. Takes precedence over `tolerance`.
+    pub max: Option<f32>,
+}
+
+impl Ratio {
+    pub fn bounds(&self, default_tolerance: f32) -> std::result::Result<(f32, f32), String> {
+        let tolerance = |default: f32| {
+            let tolerance = self.tolerance.unwrap_or(default);
+            if tolerance < 0. {
+                return Err(format!(
+                    "tolerance of '{}' must not be negative",
+                    self.ratio
+                ));
+            }
+            Ok(tolerance)
+        };
+        let (min, max) = match self.ratio.trim().to_lowercase().as_str() {
+            "landscape" => (1. + tolerance(0.01)?, f32::INFINITY),
+            "portrait" => (0., 1. - tolerance(0.01)?),
+            "square" => {
+                let tolerance = tolerance(0.05)?;
+                (1. - tolerance, 1. + tolerance)
+            }
+            "" if self.min.is_none() && self.max.is_none() => {
+                return Err("a ratio entry needs `ratio`, `min` or `max`".to_string());
+            }
+            // only min and max
+            "" => (0., f32::INFINITY),
+            ratio => {
+                let ar = Ratio::parse(ratio)
+                    .ok_or_else(|| format!("'{}' is not a ratio like 16:9 or 1.78", ratio))?;
+                let tolerance = tolerance(default_tolerance)?;
+                (ar - tolerance, ar + tolerance)
+            }
+        };
+        let (min, max) = (self.min.unwrap_or(min), self.max.unwrap_or(max));
+        if min > max {
+            return Err(format!(
+                "min {} of '{}' is greater than max {}",
+                min, self.ratio, max
+            ));
+        }
+        Ok((min, max))
+    }
+
+    fn parse(ratio: &str) -> Option<f32> {
+        let ar = match ratio.split_once([':', '/', 'x']) {
+            Some((width, height)) => {
+                let (width, height) = (
+                    width.trim().parse::<f32>().ok()?,
+                    height.trim().parse::<f32>().ok()?,
+                );
+                if height == 0. {
+                    return None;
+                }
+                width / height
+            }
+            None => ratio.parse::<f32>().ok()?,
+        };
+        if ar.is_finite() && ar > 0. {
+            Some(ar)
+        } else {
+            None
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct MinimumSize {
     pub enable: bool,
     pub minimum_height: usize,
     pub minimum_width: usize,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// 0 means no limit for that side.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct MaximumSize {
+    pub enable: bool,
+    #[serde(default)]
+    pub maximum_height: usize,
+    #[serde(default)]
+    pub maximum_width: usize,
+}
+
+/// Limits in bytes. 0 means no limit.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FileSize {
+    pub enable: bool,
+    #[serde(default)]
+    pub minimum_bytes: u64,
+    #[serde(default)]
+    pub maximum_bytes: u64,
+}
+
+/// Filters on the post itself. Unset values don't filter.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Filters {
+    pub skip_stickied: bool,
+    pub min_score: Option<i64>,
+    pub min_upvote_ratio: Option<f64>,
+    pub max_age_hours: Option<u64>,
+    pub required_flairs: Vec<String>,
+    pub excluded_flairs: Vec<String>,
+    pub title_include: Vec<String>,
+    pub title_exclude: Vec<String>,
+    pub allowed_authors: Vec<String>,
+    pub blocked_authors: Vec<String>,
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Filters {
+            skip_stickied: true,
+            min_score: None,
+            min_upvote_ratio: None,
+            max_age_hours: None,
+            required_flairs: Vec::new(),
+            excluded_flairs: Vec::new(),
+            title_include: Vec::new(),
+            title_exclude: Vec::new(),
+            allowed_authors: Vec::new(),
+            blocked_authors: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct VerifyDimensions {
+    pub enable: bool,
+    #[serde(default)]
+    pub download_without_preview: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Validation {
+    pub enable: bool,
+    #[serde(default)]
+    pub placeholders: Vec<Placeholder>,
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Validation {
+            enable: true,
+            placeholders: Vec::new(),
+        }
+    }
+}
+
+/// An image servers return in place of a removed image.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Placeholder {
+    pub size: u64,
+    /// hex encoded.
+    #[serde(default)]
+    pub sha256: String,
+    /// matches every file of `size` bytes, whatever its hash. Must be set explicitly, since real images can have that size too.
+    #[serde(default)]
+    pub any_content: bool,
+}
+
+impl Placeholder {
+    pub fn matches(&self, size: u64, sha256: &str) -> bool {
+        self.size == size
+            && (self.any_content
+                || (!self.sha256.trim().is_empty()
+                    && self.sha256.trim().eq_ignore_ascii_case(sha256)))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Advanced {
     pub user_agent: String,
+    #[serde(default)]
+    pub imgur_client_id: String,
+    #[serde(default)]
+    pub reddit_client_id: String,
+    #[serde(default)]
+    pub reddit_client_secret: String,
+    #[serde(default)]
+    pub reddit_username: String,
+    #[serde(default)]
+    pub reddit_password: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct SymbolicLink {
     pub enable: bool,
     pub use_custom_path: bool,
     pub custom_path: String,
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct History {
+    pub enable: bool,
+    #[serde(default)]
+    pub path: String,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            enable: true,
+            path: String::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Dedup {
+    pub enable: bool,
+    #[serde(default)]
+    pub action: DedupAction,
+}
+
+/// What happens to a downloaded image that has the same content as an image already saved.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupAction {
+    /// replace the duplicate with a hard link to the first copy.
+    #[default]
+    Hardlink,
+    /// replace the duplicate with a symbolic link to the first copy.
+    Symlink,
+    /// delete the duplicate.
+    Skip,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PerceptualHash {
+    pub enable: bool,
+    #[serde(default)]
+    pub algorithm: HashAlgorithm,
+    #[serde(default = "default_hamming_threshold")]
+    pub threshold: u32,
+}
+
+impl Default for PerceptualHash {
+    fn default() -> Self {
+        PerceptualHash {
+            enable: false,
+            algorithm: HashAlgorithm::default(),
+            threshold: default_hamming_threshold(),
+        }
+    }
+}
+
+fn default_hamming_threshold() -> u32 {
+    8
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// compares each pixel to the mean brightness. Fastest, but least accurate.
+    Ahash,
+    /// compares each pixel to its neighbour.
+    #[default]
+    Dhash,
+    /// compares the low frequencies of the image. Slowest, but survives more edits.
+    Phash,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Ahash => "ahash",
+            HashAlgorithm::Dhash => "dhash",
+            HashAlgorithm::Phash => "phash",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Log {
+    pub level: String,
+    pub format: String,
+    pub file: String,
+}
+
+impl Log {
+    pub fn is_json(&self) -> bool {
+        self.format.eq_ignore_ascii_case("json")
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            level: "info".to_string(),
+            format: "text".to_string(),
+            file: String::new(),
+        }
+    }
+}
+
+/// Per subreddit overrides. Unset values fall back to the global config.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Subreddit {
+    pub name: String,
+    pub sort: Option<Sort>,
+    pub time_window: Option<TimeWindow>,
+    pub nsfw: Option<bool>,
+    pub aspect_ratio: Option<AspectRatio>,
+    pub minimum_size: Option<MinimumSize>,
+    pub maximum_size: Option<MaximumSize>,
+    pub filters: Option<Filters>,
+    pub symbolic_link: Option<SymbolicLink>,
+}
+
+/// Where a source's posts are listed from.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// `/r/{subreddit}`
+    #[default]
+    Subreddit,
+    /// `/r/{a+b+c}`, the posts of several subreddits in one listing.
+    Multireddit,
+    /// `/r/{subreddit}/search?q={query}&restrict_sr=1`, or the search of all of reddit when `subreddit` is empty.
+    Search,
+    /// `/user/{user}/submitted`
+    User,
+    /// a single post by its permalink.
+    Post,
+}
+
+/// A listing to download from. Unset overrides fall back to the global config, like [`Subreddit`].
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Source {
+    pub kind: SourceKind,
+    /// used in logs and as the `{source}` placeholder. Derived from the listing when empty, see [`Source::name`].
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub subreddit: String,
+    #[serde(default)]
+    pub subreddits: Vec<String>,
+    #[serde(default)]
+    pub query: String,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub url: String,
+    pub sort: Option<Sort>,
+    pub time_window: Option<TimeWindow>,
+    pub nsfw: Option<bool>,
+    pub directory_template: Option<String>,
+    pub aspect_ratio: Option<AspectRatio>,
+    pub minimum_size: Option<MinimumSize>,
+    pub maximum_size: Option<MaximumSize>,
+    pub filters: Option<Filters>,
+    pub symbolic_link: Option<SymbolicLink>,
+}
+
+impl Source {
+    pub fn subreddit(name: &str) -> Source {
+        Source {
+            subreddit: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn name(&self) -> String {
+        if !self.name.trim().is_empty() {
+            return self.name.trim().to_string();
+        }
+        match self.kind {
+            SourceKind::Subreddit => self.subreddit.clone(),
+            SourceKind::Multireddit => self.subreddits.join("+"),
+            SourceKind::Search if self.subreddit.trim().is_empty() => {
+                format!("search {}", self.query.trim())
+            }
+            SourceKind::Search => format!("{} search {}", self.subreddit, self.query.trim()),
+            SourceKind::User => format!("u_{}", self.user),
+            SourceKind::Post => format!("post_{}", self.post_id().unwrap_or_default()),
+        }
+    }
+
+    /// Id of the post a `post` source points to. `url` may be a permalink, a `redd.it` short link or the id itself.
+    pub fn post_id(&self) -> Option<String> {
+        let url = self.url.trim();
+        let path = url
+            .split_once("://")
+            .map(|(_, rest)| {
+                rest.split_once('/')
+                    .map(|(_, path)| path)
+                    .unwrap_or_default()
+            })
+            .unwrap_or(url);
+        let segments = path
+            .split(['?', '#'])
+            .next()
+            .unwrap_or_default()
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        let id = match segments.iter().position(|s| *s == "comments") {
+            Some(i) => segments.get(i + 1).copied(),
+            None if segments.len() == 1 => segments.first().map(|id| id.trim_start_matches("t3_")),
+            None => None,
+        }?;
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        Some(id.to_lowercase())
+    }
+
+    /// Checks that the fields the kind needs are set.
+    pub fn check(&self) -> std::result::Result<(), String> {
+        let missing = |field: &str| Err(format!("{} source needs `{}`", self.kind.as_str(), field));
+        match self.kind {
+            SourceKind::Subreddit if self.subreddit.trim().is_empty() => {
+                Err("subreddit name must not be empty".to_string())
+            }
+            SourceKind::Multireddit
+                if self.subreddits.is_empty()
+                    || self.subreddits.iter().any(|sub| sub.trim().is_empty()) =>
+            {
+                missing("subreddits")
+            }
+            SourceKind::Search if self.query.trim().is_empty() => missing("query"),
+            SourceKind::User if self.user.trim().is_empty() => missing("user"),
+            SourceKind::Post if self.post_id().is_none() => Err(format!(
+                "post source url '{}' is not a link to a reddit post",
+                self.url
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl SourceKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Self::Subreddit => "subreddit",
+            Self::Multireddit => "multireddit",
+            Self::Search => "search",
+            Self::User => "user",
+            Self::Post => "post",
+        }
+    }
+}
+
+impl From<&Subreddit> for Source {
+    fn from(sub: &Subreddit) -> Source {
+        Source {
+            sort: sub.sort,
+            time_window: sub.time_window,
+            nsfw: sub.nsfw,
+            aspect_ratio: sub.aspect_ratio.clone(),
+            minimum_size: sub.minimum_size.clone(),
+            maximum_size: sub.maximum_size.clone(),
+            filters: sub.filters.clone(),
+            symbolic_link: sub.symbolic_link.clone(),
+            ..Source::subreddit(sub.name.as_str())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratio(ratio: &str, tolerance: Option<f32>, min: Option<f32>, max: Option<f32>) -> Ratio {
+        Ratio {
+            ratio: ratio.to_string(),
+            tolerance,
+            min,
+            max,
+        }
+    }
+
+    fn close(actual: (f32, f32), expected: (f32, f32)) -> bool {
+        (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4
+    }
+
+    #[test]
+    fn ratios_are_parsed_in_every_notation() {
+        for notation in ["16:9", "16/9", "16x9", " 16 : 9 "] {
+            let ar = Ratio::parse(notation).unwrap();
+            assert!((ar - 16. / 9.).abs() < 1e-4, "{}", notation);
+        }
+        assert_eq!(Ratio::parse("1.78"), Some(1.78));
+    }
+
+    #[test]
+    fn invalid_ratios_are_not_parsed() {
+        for notation in ["16:0", "0", "-1.5", "0:9", "wide", "16:", "inf", "NaN"] {
+            assert_eq!(Ratio::parse(notation), None, "{}", notation);
+        }
+    }
+
+    #[test]
+    fn presets_have_their_own_tolerance() {
+        assert_eq!(
+            ratio("landscape", None, None, None).bounds(0.5),
+            Ok((1.01, f32::INFINITY))
+        );
+        assert_eq!(
+            ratio("Portrait", None, None, None).bounds(0.5),
+            Ok((0., 0.99))
+        );
+        assert_eq!(
+            ratio("square", None, None, None).bounds(0.5),
+            Ok((0.95, 1.05))
+        );
+        assert_eq!(
+            ratio("square", Some(0.1), None, None).bounds(0.5),
+            Ok((0.9, 1.1))
+        );
+    }
+
+    #[test]
+    fn ratio_uses_the_default_tolerance() {
+        let bounds = ratio("16x9", None, None, None).bounds(0.1).unwrap();
+        assert!(
+            close(bounds, (16. / 9. - 0.1, 16. / 9. + 0.1)),
+            "{:?}",
+            bounds
+        );
+        let bounds = ratio("1.78", Some(0.02), None, None).bounds(0.1).unwrap();
+        assert!(close(bounds, (1.76, 1.8)), "{:?}", bounds);
+    }
+
+    #[test]
+    fn min_and_max_take_precedence() {
+        assert_eq!(
+            ratio("16:9", None, Some(1.5), Some(2.)).bounds(0.1),
+            Ok((1.5, 2.))
+        );
+        assert_eq!(
+            ratio("", None, Some(1.5), None).bounds(0.1),
+            Ok((1.5, f32::INFINITY))
+        );
+        assert_eq!(ratio("", None, None, Some(0.8)).bounds(0.1), Ok((0., 0.8)));
+    }
+
+    #[test]
+    fn invalid_entries_are_errors() {
+        assert!(ratio("", None, None, None).bounds(0.1).is_err());
+        assert!(ratio("", Some(0.1), None, None).bounds(0.1).is_err());
+        assert!(ratio("16:0", None, None, None).bounds(0.1).is_err());
+        assert!(ratio("16:9", Some(-0.1), None, None).bounds(0.1).is_err());
+        assert!(ratio("", None, Some(2.), Some(1.)).bounds(0.1).is_err());
+    }
+
+    #[test]
+    fn section_without_valid_ratios_is_an_error() {
+        let section = |ratios: Vec<Ratio>, width_aspect: usize| AspectRatio {
+            enable: true,
+            height_aspect: 9,
+            width_aspect,
+            ratio_range: 0.1,
+            ratios,
+        };
+        assert!(section(Vec::new(), 0).bounds().is_err());
+        assert!(section(vec![Ratio::default()], 16).bounds().is_err());
+        assert!(section(
+            vec![
+                ratio("square", None, None, None),
+                ratio("wide", None, None, None)
+            ],
+            16
+        )
+        .bounds()
+        .is_err());
+        assert_eq!(section(Vec::new(), 16).bounds().unwrap().len(), 1);
+    }
+}
@@ -1,5 +1,6 @@
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Sort {
@@ -10,23 +11,47 @@ pub enum Sort {
     Top,
 }
 
+impl Sort {
+    /// Only `top` and `controversial` listings can be narrowed down by a time window.
+    pub fn has_time_window(&self) -> bool {
+        matches!(*self, Self::Top | Self::Controversial)
+    }
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hot" => Ok(Sort::Hot),
+            "new" => Ok(Sort::New),
+            "rising" => Ok(Sort::Rising),
+            "controversial" => Ok(Sort::Controversial),
+            "top" => Ok(Sort::Top),
+            _ => Err(format!(
+                "invalid sort '{}'. valid values: hot, new, rising, controversial, top",
+                s
+            )),
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for Sort {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let s = String::deserialize(deserializer)
-            .unwrap_or_default()
-            .to_lowercase();
+        let s = String::deserialize(deserializer).unwrap_or_default();
+        Ok(s.parse().unwrap_or(Sort::Hot))
+    }
+}
 
-        let sort = match s.as_str() {
-            "new" => Sort::New,
-            "rising" => Sort::Rising,
-            "controversial" => Sort::Controversial,
-            "top" => Sort::Top,
-            _ => Sort::Hot,
-        };
-        Ok(sort)
+impl Serialize for Sort {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_str())
     }
 }
 
@@ -41,3 +66,71 @@ impl fmt::Display for Sort {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy)]
+pub enum TimeWindow {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+    All,
+}
+
+impl Default for TimeWindow {
+    /// Reddit uses `day` when the time window is not specified.
+    fn default() -> Self {
+        Self::Day
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hour" => Ok(TimeWindow::Hour),
+            "day" => Ok(TimeWindow::Day),
+            "week" => Ok(TimeWindow::Week),
+            "month" => Ok(TimeWindow::Month),
+            "year" => Ok(TimeWindow::Year),
+            "all" => Ok(TimeWindow::All),
+            _ => Err(format!(
+                "invalid time window '{}'. valid values: hour, day, week, month, year, all",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeWindow {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer).unwrap_or_default();
+        Ok(s.parse().unwrap_or_default())
+    }
+}
+
+impl Serialize for TimeWindow {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Hour => write!(f, "hour"),
+            Self::Day => write!(f, "day"),
+            Self::Week => write!(f, "week"),
+            Self::Month => write!(f, "month"),
+            Self::Year => write!(f, "year"),
+            Self::All => write!(f, "all"),
+        }
+    }
+}
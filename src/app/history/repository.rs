@@ -0,0 +1,395 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::app::{config::model::Config, reddit::models::meta::DownloadMeta};
+
+static SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS downloads (
+    post_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    subreddit TEXT NOT NULL,
+    title TEXT NOT NULL,
+    author TEXT NOT NULL,
+    permalink TEXT NOT NULL,
+    nsfw INTEGER NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    file_path TEXT NOT NULL,
+    downloaded_at INTEGER NOT NULL,
+    PRIMARY KEY (post_id, url)
+);
+CREATE TABLE IF NOT EXISTS files (
+    file_path TEXT NOT NULL PRIMARY KEY,
+    sha256 TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    modified INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS files_sha256 ON files (sha256);
+CREATE TABLE IF NOT EXISTS perceptual_hashes (
+    file_path TEXT NOT NULL PRIMARY KEY,
+    algorithm TEXT NOT NULL,
+    hash INTEGER NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS duplicates (
+    post_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    file_path TEXT NOT NULL,
+    original_path TEXT NOT NULL,
+    action TEXT NOT NULL,
+    decided_at INTEGER NOT NULL,
+    PRIMARY KEY (post_id, url)
+);
+"#;
+
+/// Remembers downloaded images, so images the user removed are not downloaded again.
+/// Every method is a no-op when history is disabled.
+pub struct HistoryRepository {
+    conn: Option<Mutex<Connection>>,
+}
+
+impl HistoryRepository {
+    pub fn new(config: &Config) -> Result<HistoryRepository> {
+        if !config.history.enable {
+            return Ok(HistoryRepository { conn: None });
+        }
+        let p = config.get_history_path();
+        if let Some(dir) = p.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create directory on {}", dir.display()))?;
+        }
+        let conn = Connection::open(p.as_path())
+            .with_context(|| format!("failed to open history database on {}", p.display()))?;
+        conn.execute_batch(SCHEMA).with_context(|| {
+            format!(
+                "failed to create history database tables on {}",
+                p.display()
+            )
+        })?;
+        Ok(HistoryRepository {
+            conn: Some(Mutex::new(conn)),
+        })
+    }
+
+    /// Checks whether the image of the post has been downloaded before, even if the file no longer exists.
+    pub fn contains(&self, download: &DownloadMeta) -> Result<bool> {
+        let conn = match self.conn.as_ref() {
+            Some(conn) => conn.lock().unwrap(),
+            None => return Ok(false),
+        };
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM downloads WHERE post_id = ?1 AND url = ?2",
+                params![download.post_id, download.url],
+                |_| Ok(()),
+            )
+            .optional()
+            .with_context(|| {
+                format!(
+                    "[{}] failed to query download history for {}",
+                    download.subreddit_name, download.url
+                )
+            })?;
+        Ok(found.is_some())
+    }
+
+    /// Returns the post id and url of the download recorded with the file, or `None` when the file is unknown.
+    pub fn file_owner(&self, file_path: &Path) -> Result<Option<(String, String)>> {
+        let conn = match self.conn.as_ref() {
+            Some(conn) => conn.lock().unwrap(),
+            None => return Ok(None),
+        };
+        conn.query_row(
+            "SELECT post_id, url FROM downloads WHERE file_path = ?1",
+            params![file_path.display().to_string()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .with_context(|| {
+            format!(
+                "failed to query download history for {}",
+                file_path.display()
+            )
+        })
+    }
+
+    pub fn record(&self, download: &DownloadMeta, file_path: &Path) -> Result<()> {
+        let conn = match self.conn.as_ref() {
+            Some(conn) => conn.lock().unwrap(),
+            None => return Ok(()),
+        };
+        conn.execute(
+            "INSERT OR REPLACE INTO downloads (post_id, url, subreddit, title, author, permalink, nsfw, width, height, file_path, downloaded_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                download.post_id,
+                download.url,
+                download.subreddit_name,
+                download.title,
+                download.author,
+                download.post_link,
+                download.nsfw,
+                // 0 when the size is unknown
+                download.image_size.map(|(width, _)| width).unwrap_or_default(),
+                download.image_size.map(|(_, height)| height).unwrap_or_default(),
+                file_path.display().to_string(),
+                now(),
+            ],
+        )
+        .with_context(|| {
+            format!(
+                "[{}] failed to record download history for {}",
+                download.subreddit_name, download.url
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Checks whether the hash of the file is stored and the file has not changed since.
+    pub fn has_file_hash(&self, file: &FileHash) -> Result<bool> {
+        let conn = match self.conn.as_ref() {
+            Some(conn) => conn.lock().unwrap(),
+            None => return Ok(false),
+        };
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM files WHERE file_path = ?1 AND size = ?2 AND modified = ?3",
+                params![file.path.display().to_string(), file.size, file.modified],
+                |_| Ok(()),
+            )
+            .optional()
+            .with_context(|| format!("failed to query file hash of {}", file.path.display()))?;
+        Ok(found.is_some())
+    }
+
+    pub fn record_file_hash(&self, file: &FileHash) -> Result<()> {
+        let conn = match self.conn.as_ref() {
+            Some(conn) => conn.lock().unwrap(),
+            None => return Ok(()),
+        };
+        HistoryRepository::insert_file_hash(&conn, file)
+    }
+
+    /// Returns the first copy of the file's content when one still exists. Otherwise the file is stored as the first copy.
+    /// Both happen under one lock, so two copies downloaded at the same time can't both become the first copy.
+    pub fn find_or_record_file_hash(&self, file: &FileHash) -> Result<Option<PathBuf>> {
+        let conn = match self.conn.as_ref() {
+            Some(conn) => conn.lock().unwrap(),
+            None => return Ok(None),
+        };
+        let path = file.path.display().to_string();
+        let copies = conn
+            .prepare(
+                "SELECT file_path FROM files WHERE sha256 = ?1 AND file_path != ?2 ORDER BY rowid",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map(params![file.sha256, path], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .with_context(|| format!("failed to query file hashes for {}", file.path.display()))?;
+        for copy in copies.into_iter() {
+            let copy = PathBuf::from(copy);
+            if copy.is_file() {
+                return Ok(Some(copy));
+            }
+            // the user removed or moved the first copy
+            conn.execute(
+                "DELETE FROM files WHERE file_path = ?1",
+                params![copy.display().to_string()],
+            )
+            .with_context(|| format!("failed to remove file hash of {}", copy.display()))?;
+        }
+        HistoryRepository::insert_file_hash(&conn, file)?;
+        Ok(None)
+    }
+
+    /// Returns the saved image most similar to `image`, i.e. with the smallest Hamming distance up to `threshold`.
+    /// `image` is stored when there is no similar image, or when it has more pixels than the similar one, which it then replaces.
+    /// Both happen under one lock, like [`HistoryRepository::find_or_record_file_hash`].
+    pub fn find_similar_or_record(
+        &self,
+        image: &ImageHash,
+        threshold: u32,
+    ) -> Result<Option<ImageHash>> {
+        let conn = match self.conn.as_ref() {
+            Some(conn) => conn.lock().unwrap(),
+            None => return Ok(None),
+        };
+        let path = image.path.display().to_string();
+        let candidates = conn
+            .prepare(
+                "SELECT file_path, hash, width, height FROM perceptual_hashes WHERE algorithm = ?1 AND file_path != ?2",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map(params![image.algorithm, path], |row| {
+                    Ok(ImageHash {
+                        path: PathBuf::from(row.get::<_, String>(0)?),
+                        algorithm: image.algorithm.clone(),
+                        hash: row.get::<_, i64>(1)? as u64,
+                        width: row.get(2)?,
+                        height: row.get(3)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .with_context(|| format!("failed to query perceptual hashes for {}", image.path.display()))?;
+
+        let mut similar: Option<ImageHash> = None;
+        for candidate in candidates.into_iter() {
+            let distance = (candidate.hash ^ image.hash).count_ones();
+            if distance > threshold {
+                continue;
+            }
+            if !candidate.path.is_file() {
+                conn.execute(
+                    "DELETE FROM perceptual_hashes WHERE file_path = ?1",
+                    params![candidate.path.display().to_string()],
+                )
+                .with_context(|| {
+                    format!(
+                        "failed to remove perceptual hash of {}",
+                        candidate.path.display()
+                    )
+                })?;
+                continue;
+            }
+            let closer = similar
+                .as_ref()
+                .map(|s| distance < (s.hash ^ image.hash).count_ones())
+                .unwrap_or(true);
+            if closer {
+                similar = Some(candidate);
+            }
+        }
+
+        if let Some(found) = similar.as_ref() {
+            if image.pixels() <= found.pixels() {
+                return Ok(similar);
+            }
+            conn.execute(
+                "DELETE FROM perceptual_hashes WHERE file_path = ?1",
+                params![found.path.display().to_string()],
+            )
+            .with_context(|| {
+                format!(
+                    "failed to remove perceptual hash of {}",
+                    found.path.display()
+                )
+            })?;
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO perceptual_hashes (file_path, algorithm, hash, width, height) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![path, image.algorithm, image.hash as i64, image.width, image.height],
+        )
+        .with_context(|| format!("failed to record perceptual hash of {}", image.path.display()))?;
+        Ok(similar)
+    }
+
+    /// `post_id` and `url` are of the removed or linked copy.
+    pub fn record_duplicate(
+        &self,
+        post_id: &str,
+        url: &str,
+        file_path: &Path,
+        original_path: &Path,
+        action: &str,
+    ) -> Result<()> {
+        let conn = match self.conn.as_ref() {
+            Some(conn) => conn.lock().unwrap(),
+            None => return Ok(()),
+        };
+        conn.execute(
+            "INSERT OR REPLACE INTO duplicates (post_id, url, file_path, original_path, action, decided_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                post_id,
+                url,
+                file_path.display().to_string(),
+                original_path.display().to_string(),
+                action,
+                now(),
+            ],
+        )
+        .with_context(|| format!("failed to record duplicate {}", url))?;
+        Ok(())
+    }
+
+    /// Moves the duplicates that are symbolic links to `original` over to `replacement`, and returns their paths.
+    pub fn move_linked_duplicates(
+        &self,
+        original: &Path,
+        replacement: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        let conn = match self.conn.as_ref() {
+            Some(conn) => conn.lock().unwrap(),
+            None => return Ok(Vec::new()),
+        };
+        let original = original.display().to_string();
+        let links = conn
+            .prepare(
+                "SELECT file_path FROM duplicates WHERE original_path = ?1 AND action = 'symlink'",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map(params![original], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .with_context(|| format!("failed to query duplicates of {}", original))?;
+        conn.execute(
+            "UPDATE duplicates SET original_path = ?1 WHERE original_path = ?2 AND action = 'symlink'",
+            params![replacement.display().to_string(), original],
+        )
+        .with_context(|| format!("failed to move duplicates of {}", original))?;
+        Ok(links.into_iter().map(PathBuf::from).collect())
+    }
+
+    fn insert_file_hash(conn: &Connection, file: &FileHash) -> Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO files (file_path, sha256, size, modified) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                file.path.display().to_string(),
+                file.sha256,
+                file.size,
+                file.modified
+            ],
+        )
+        .with_context(|| format!("failed to record file hash of {}", file.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Content hash of a saved file. `size` and `modified` tell whether the file changed after it was hashed.
+pub struct FileHash {
+    pub path: PathBuf,
+    pub sha256: String,
+    pub size: i64,
+    pub modified: i64,
+}
+
+/// Perceptual hash of a saved image, see [`crate::app::config::model::PerceptualHash`].
+#[derive(Clone)]
+pub struct ImageHash {
+    pub path: PathBuf,
+    pub algorithm: String,
+    pub hash: u64,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageHash {
+    pub fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
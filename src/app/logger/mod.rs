@@ -0,0 +1,68 @@
+use std::fs::{self, OpenOptions};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::{Context, Error, Result};
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, Layer, Registry};
+
+use crate::app::config::model::Log;
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Sends logs to stdout, and to the log file if one is configured.
+pub fn init(log: &Log) -> Result<()> {
+    let level = LevelFilter::from_str(log.level.as_str()).map_err(|_| {
+        Error::msg(format!(
+            "invalid log level '{}'. valid values: error, warn, info, debug, trace, off",
+            log.level
+        ))
+    })?;
+    let json = match log.format.to_lowercase().as_str() {
+        "text" => false,
+        "json" => true,
+        _ => {
+            return Err(Error::msg(format!(
+                "invalid log format '{}'. valid values: text, json",
+                log.format
+            )))
+        }
+    };
+
+    let mut layers: Vec<BoxedLayer> = vec![new_layer(json, true, std::io::stdout)];
+    if !log.file.trim().is_empty() {
+        let b = shellexpand::full(log.file.as_str())?;
+        let p = std::path::Path::new(b.as_ref());
+        if let Some(dir) = p.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create directory on {}", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(p)
+            .with_context(|| format!("failed to open log file on {}", p.display()))?;
+        layers.push(new_layer(json, false, Mutex::new(file)));
+    }
+
+    tracing_subscriber::registry()
+        .with(layers.with_filter(level))
+        .try_init()
+        .context("failed to initialize logger")?;
+    Ok(())
+}
+
+fn new_layer<W>(json: bool, ansi: bool, writer: W) -> BoxedLayer
+where
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer()
+        .with_target(false)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    if json {
+        // fields are flattened so every line has `subreddit`, `url`, etc. at the top level.
+        layer.json().flatten_event(true).boxed()
+    } else {
+        layer.boxed()
+    }
+}
@@ -1,3 +1,6 @@
+pub mod cli;
 pub mod config;
+pub mod history;
+pub mod logger;
 pub mod reddit;
 pub mod service;
@@ -1,7 +1,10 @@
 use crate::app::config::model::Config;
+use std::sync::Arc;
 use std::time::Duration;
 use ureq::{Agent, AgentBuilder};
 
+use super::oauth::OAuth;
+
 pub fn new_agent(c: &Config) -> Agent {
     AgentBuilder::new()
         .user_agent(c.advanced.user_agent.as_str())
@@ -9,3 +12,9 @@ pub fn new_agent(c: &Config) -> Agent {
         .timeout(Duration::from_millis(c.downloads.download_timeout))
         .build()
 }
+
+/// The reddit app configured in `[advanced]`, fetching its tokens with `agent`. `None` when no app is configured.
+/// The agent can't add the token to requests itself, so [`Repository`](super::repository::Repository) sets it on api requests.
+pub fn new_oauth(agent: &Agent, c: &Config) -> Option<Arc<OAuth>> {
+    OAuth::from_config(agent.clone(), c).map(Arc::new)
+}
@@ -1,3 +1,4 @@
 pub mod agent;
 pub mod models;
+pub mod oauth;
 pub mod repository;
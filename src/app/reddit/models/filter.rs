@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use tracing::{debug, info};
+
+use crate::app::config::model::Filters;
+
+use super::listing::ChildrenData;
+use super::meta::DownloadMeta;
+
+/// The reasons a post, or an item of a gallery post, is not downloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Filter {
+    Video,
+    Nsfw,
+    NoImage,
+    /// the link's domain is blocked, or not in the allowed domains.
+    Domain,
+    /// the downloaded file is not an image this app can read the dimensions of.
+    UnreadableImage,
+    /// the server responded with something other than an image, like an html page.
+    NotAnImage,
+    /// the image is a known "image not found" placeholder.
+    Placeholder,
+    AspectRatio,
+    MinimumSize,
+    MaximumSize,
+    /// the image file is smaller or larger than the limits.
+    FileSize,
+    Stickied,
+    Score,
+    UpvoteRatio,
+    Age,
+    Flair,
+    Title,
+    Author,
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Video => write!(f, "video"),
+            Self::Nsfw => write!(f, "nsfw"),
+            Self::NoImage => write!(f, "no image"),
+            Self::Domain => write!(f, "domain"),
+            Self::UnreadableImage => write!(f, "unreadable image"),
+            Self::NotAnImage => write!(f, "not an image"),
+            Self::Placeholder => write!(f, "placeholder image"),
+            Self::AspectRatio => write!(f, "aspect ratio"),
+            Self::MinimumSize => write!(f, "minimum size"),
+            Self::MaximumSize => write!(f, "maximum size"),
+            Self::FileSize => write!(f, "file size"),
+            Self::Stickied => write!(f, "stickied"),
+            Self::Score => write!(f, "score"),
+            Self::UpvoteRatio => write!(f, "upvote ratio"),
+            Self::Age => write!(f, "age"),
+            Self::Flair => write!(f, "flair"),
+            Self::Title => write!(f, "title"),
+            Self::Author => write!(f, "author"),
+        }
+    }
+}
+
+/// Error for a download that is thrown away because of a filter, as opposed to a download that failed.
+#[derive(Debug)]
+pub struct Rejected {
+    pub filter: Filter,
+    pub reason: String,
+}
+
+impl Rejected {
+    pub fn new<S: Into<String>>(filter: Filter, reason: S) -> Rejected {
+        Rejected {
+            filter,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.filter, self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Downloads found in listings, and how many posts each filter threw away.
+#[derive(Default)]
+pub struct Collected {
+    pub listings: usize,
+    pub posts: usize,
+    pub downloads: Vec<DownloadMeta>,
+    pub filtered: BTreeMap<Filter, usize>,
+    /// listing pages after the first that could not be fetched. What the earlier pages found is kept.
+    pub failed_pages: usize,
+}
+
+impl Collected {
+    pub fn reject(&mut self, subreddit: &str, post_id: &str, filter: Filter) {
+        debug!(subreddit, post_id, reason = %filter, "post filtered out");
+        *self.filtered.entry(filter).or_default() += 1;
+    }
+
+    /// Like [`Collected::reject`], with what about the post failed the filter.
+    pub fn reject_with_detail(
+        &mut self,
+        subreddit: &str,
+        post_id: &str,
+        filter: Filter,
+        detail: &str,
+    ) {
+        info!(subreddit, post_id, reason = %filter, detail, "post filtered out");
+        *self.filtered.entry(filter).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: Collected) {
+        self.listings += other.listings;
+        self.posts += other.posts;
+        self.failed_pages += other.failed_pages;
+        self.downloads.extend(other.downloads);
+        for (filter, count) in other.filtered.into_iter() {
+            *self.filtered.entry(filter).or_default() += count;
+        }
+    }
+}
+
+/// The `[filters]` section with its regular expressions compiled.
+pub struct PostFilter {
+    filters: Filters,
+    title_include: Vec<Regex>,
+    title_exclude: Vec<Regex>,
+}
+
+impl PostFilter {
+    pub fn new(filters: &Filters) -> Result<PostFilter> {
+        let compile = |patterns: &Vec<String>, name: &str| {
+            patterns
+                .iter()
+                .map(|p| {
+                    Regex::new(p.as_str())
+                        .with_context(|| format!("invalid regular expression in {}: {}", name, p))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(PostFilter {
+            filters: filters.clone(),
+            title_include: compile(&filters.title_include, "title_include")?,
+            title_exclude: compile(&filters.title_exclude, "title_exclude")?,
+        })
+    }
+
+    /// Returns the filter the post failed with a description of why.
+    pub fn check(&self, post: &ChildrenData) -> Result<(), (Filter, String)> {
+        let f = &self.filters;
+        if f.skip_stickied && post.stickied {
+            return Err((Filter::Stickied, "post is stickied".to_string()));
+        }
+        if let Some(min_score) = f.min_score {
+            if post.score < min_score {
+                return Err((
+                    Filter::Score,
+                    format!("score {} is below {}", post.score, min_score),
+                ));
+            }
+        }
+        if let Some(min_upvote_ratio) = f.min_upvote_ratio {
+            if post.upvote_ratio < min_upvote_ratio {
+                return Err((
+                    Filter::UpvoteRatio,
+                    format!(
+                        "upvote ratio {} is below {}",
+                        post.upvote_ratio, min_upvote_ratio
+                    ),
+                ));
+            }
+        }
+        if let Some(max_age_hours) = f.max_age_hours {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or_default();
+            let age_hours = ((now - post.created_utc) / 3600.0).max(0.0);
+            if age_hours > max_age_hours as f64 {
+                return Err((
+                    Filter::Age,
+                    format!(
+                        "post is {:.0} hours old, more than {}",
+                        age_hours, max_age_hours
+                    ),
+                ));
+            }
+        }
+
+        let flair = post.link_flair_text.as_deref().unwrap_or_default().trim();
+        let has_flair = |flairs: &Vec<String>| {
+            flairs
+                .iter()
+                .any(|name| name.trim().eq_ignore_ascii_case(flair))
+        };
+        if !f.required_flairs.is_empty() && !has_flair(&f.required_flairs) {
+            return Err((
+                Filter::Flair,
+                format!("flair '{}' is not one of the required flairs", flair),
+            ));
+        }
+        if has_flair(&f.excluded_flairs) {
+            return Err((Filter::Flair, format!("flair '{}' is excluded", flair)));
+        }
+
+        if !self.title_include.is_empty()
+            && !self
+                .title_include
+                .iter()
+                .any(|re| re.is_match(post.title.as_str()))
+        {
+            return Err((
+                Filter::Title,
+                "title matches none of title_include".to_string(),
+            ));
+        }
+        if let Some(re) = self
+            .title_exclude
+            .iter()
+            .find(|re| re.is_match(post.title.as_str()))
+        {
+            return Err((
+                Filter::Title,
+                format!("title matches excluded pattern '{}'", re.as_str()),
+            ));
+        }
+
+        let is_author = |authors: &Vec<String>| {
+            authors.iter().any(|name| {
+                name.trim()
+                    .trim_start_matches("u/")
+                    .eq_ignore_ascii_case(post.author.as_str())
+            })
+        };
+        if !f.allowed_authors.is_empty() && !is_author(&f.allowed_authors) {
+            return Err((
+                Filter::Author,
+                format!("author {} is not allowed", post.author),
+            ));
+        }
+        if is_author(&f.blocked_authors) {
+            return Err((Filter::Author, format!("author {} is blocked", post.author)));
+        }
+        Ok(())
+    }
+}
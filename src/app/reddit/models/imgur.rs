@@ -0,0 +1,20 @@
+use serde::Deserialize;
+
+/// Response of imgur's `album/{id}/images` api.
+#[derive(Deserialize)]
+pub struct ImgurAlbum {
+    pub data: Vec<ImgurImage>,
+    pub success: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ImgurImage {
+    /// mime type, e.g. `image/jpeg`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub animated: bool,
+    pub width: u32,
+    pub height: u32,
+    /// direct link to the image.
+    pub link: String,
+}
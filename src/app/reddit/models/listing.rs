@@ -1,7 +1,16 @@
 use crate::app::config::model::Config;
 
+use super::filter::{Collected, Filter, PostFilter};
+use super::imgur::ImgurAlbum;
 use super::meta::DownloadMeta;
+use super::resolver::{self, Resolved};
+use super::template;
 use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// subreddit, post id and the filter that rejected the post.
+pub type RejectedPost = (String, String, Filter);
 
 #[derive(Deserialize)]
 pub struct Listing {
@@ -10,60 +19,288 @@ pub struct Listing {
 }
 
 impl Listing {
-    pub fn into_download_metas(self, config: &Config) -> Vec<DownloadMeta> {
-        let mut result: Vec<DownloadMeta> = Vec::new();
+    /// `source` is the name of the source the listing was fetched for. `post_filter` is the compiled `[filters]` section of `config`.
+    pub fn into_download_metas(
+        self,
+        source: &str,
+        config: &Config,
+        post_filter: &PostFilter,
+    ) -> Collected {
+        let mut collected = Collected {
+            listings: 1,
+            ..Default::default()
+        };
         for children in self.data.children.into_iter() {
             let data = children.data;
-            if data.is_video {
-                continue;
-            }
-            if data.over_18 && !config.downloads.nsfw {
+            collected.posts += 1;
+            if let Err((filter, detail)) = post_filter.check(&data) {
+                collected.reject_with_detail(
+                    data.subreddit.as_str(),
+                    data.id.as_str(),
+                    filter,
+                    detail.as_str(),
+                );
                 continue;
             }
-            let image_size: (u32, u32);
-            if let Some(preview) = data.preview {
-                if let Some(preview) = preview.get_image_size() {
-                    image_size = preview;
-                } else {
-                    continue;
+            for result in Listing::post_download_metas(data, source, config) {
+                match result {
+                    Ok(meta) => collected.downloads.push(meta),
+                    Err((subreddit, post_id, filter)) => {
+                        collected.reject(subreddit.as_str(), post_id.as_str(), filter)
+                    }
                 }
-            } else {
-                continue;
             }
+        }
+        collected
+    }
 
-            if !Listing::passed_aspect_ratio(image_size, config) {
-                continue;
+    fn post_download_metas(
+        data: ChildrenData,
+        source: &str,
+        config: &Config,
+    ) -> Vec<Result<DownloadMeta, RejectedPost>> {
+        let reject = |filter: Filter| vec![Err((data.subreddit.clone(), data.id.clone(), filter))];
+        if data.is_video {
+            return reject(Filter::Video);
+        }
+        if data.over_18 && !config.downloads.nsfw {
+            return reject(Filter::Nsfw);
+        }
+        if !Listing::passed_domain(data.url.as_str(), config) {
+            return reject(Filter::Domain);
+        }
+        if data.is_gallery.unwrap_or(false) {
+            return Listing::gallery_download_metas(&data, source, config);
+        }
+
+        // Pages the app can't read images from fall back to the image reddit shows in the preview.
+        let preview_url = data.preview.as_ref().and_then(|p| p.get_source_url());
+        let (url, imgur_album) = match resolver::resolve(data.url.as_str()) {
+            Resolved::Image(url) => (url, None),
+            Resolved::Video => return reject(Filter::Video),
+            Resolved::ImgurAlbum(id) if !config.advanced.imgur_client_id.trim().is_empty() => {
+                (preview_url.unwrap_or_else(|| data.url.clone()), Some(id))
             }
+            Resolved::ImgurAlbum(_) | Resolved::Unknown => match preview_url {
+                Some(url) => (url, None),
+                None => return reject(Filter::NoImage),
+            },
+        };
 
-            if !Listing::passed_mininum_size(image_size, config) {
-                continue;
+        // Without a preview the size is unknown, and is checked after download instead.
+        // Album images are checked one by one when the album is expanded.
+        let image_size = data.preview.as_ref().and_then(|p| p.get_image_size());
+        match image_size {
+            Some(_) if imgur_album.is_some() => {}
+            Some(image_size) => {
+                if let Err(filter) = Listing::check_image_size(image_size, config) {
+                    return reject(filter);
+                }
+            }
+            None if imgur_album.is_some() => {}
+            None if config.verify_dimensions.enable
+                && config.verify_dimensions.download_without_preview
+                && Listing::is_image_url(url.as_str()) => {}
+            None => return reject(Filter::NoImage),
+        }
+
+        let mut meta = DownloadMeta {
+            post_id: data.id,
+            subreddit_name: data.subreddit.clone(),
+            source: source.to_string(),
+            post_link: format!("https://reddit.com{}", data.permalink),
+            image_size,
+            filename: Listing::get_filename_from_url(url.as_str()),
+            url,
+            nsfw: data.over_18,
+            title: data.title,
+            author: data.author,
+            directory: data.subreddit,
+            created_utc: data.created_utc,
+            gallery_index: None,
+            imgur_album,
+        };
+        Listing::apply_templates(&mut meta, config);
+        vec![Ok(meta)]
+    }
+
+    /// Expands a gallery post into one download per gallery item, in the order they appear in the post.
+    fn gallery_download_metas(
+        data: &ChildrenData,
+        source: &str,
+        config: &Config,
+    ) -> Vec<Result<DownloadMeta, RejectedPost>> {
+        let rejected = |filter: Filter| (data.subreddit.clone(), data.id.clone(), filter);
+        let (gallery, media_metadata) = match (&data.gallery_data, &data.media_metadata) {
+            (Some(gallery), Some(media_metadata)) => (gallery, media_metadata),
+            _ => return vec![Err(rejected(Filter::NoImage))],
+        };
+        gallery
+            .items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| {
+                let media = media_metadata.get(&item.media_id);
+                if media.map(MediaMetadata::is_animated).unwrap_or(false) {
+                    return Err(rejected(Filter::Video));
+                }
+                let (image_size, url) = match media.and_then(|media| {
+                    Some((
+                        media.get_image_size()?,
+                        media.get_url(item.media_id.as_str())?,
+                    ))
+                }) {
+                    Some(image) => image,
+                    None => return Err(rejected(Filter::NoImage)),
+                };
+                Listing::check_image_size(image_size, config).map_err(rejected)?;
+                let mut meta = DownloadMeta {
+                    post_id: data.id.clone(),
+                    subreddit_name: data.subreddit.clone(),
+                    source: source.to_string(),
+                    post_link: format!("https://reddit.com{}", data.permalink),
+                    image_size: Some(image_size),
+                    nsfw: data.over_18,
+                    title: data.title.clone(),
+                    author: data.author.clone(),
+                    filename: Listing::get_filename_from_url(url.as_str()),
+                    directory: data.subreddit.clone(),
+                    url,
+                    created_utc: data.created_utc,
+                    gallery_index: Some(index + 1),
+                    imgur_album: None,
+                };
+                Listing::apply_templates(&mut meta, config);
+                Ok(meta)
+            })
+            .collect()
+    }
+
+    /// Replaces the download of an imgur album post with one download per image in the album, like gallery posts.
+    pub fn imgur_album_download_metas(
+        album_meta: &DownloadMeta,
+        album: ImgurAlbum,
+        config: &Config,
+    ) -> Vec<Result<DownloadMeta, RejectedPost>> {
+        let rejected = |filter: Filter| {
+            (
+                album_meta.subreddit_name.clone(),
+                album_meta.post_id.clone(),
+                filter,
+            )
+        };
+        album
+            .data
+            .into_iter()
+            .enumerate()
+            .map(|(index, image)| {
+                if image.animated || !image.kind.starts_with("image/") {
+                    return Err(rejected(Filter::Video));
+                }
+                Listing::check_image_size((image.width, image.height), config).map_err(rejected)?;
+                let mut meta = DownloadMeta {
+                    image_size: Some((image.width, image.height)),
+                    filename: Listing::get_filename_from_url(image.link.as_str()),
+                    url: image.link,
+                    directory: album_meta.subreddit_name.clone(),
+                    gallery_index: Some(index + 1),
+                    imgur_album: None,
+                    ..album_meta.clone()
+                };
+                Listing::apply_templates(&mut meta, config);
+                Ok(meta)
+            })
+            .collect()
+    }
+
+    /// Without a filename template the name from the url is kept. Gallery items are prefixed with the post id and their position,
+    /// so they stay grouped and in order.
+    fn apply_templates(meta: &mut DownloadMeta, config: &Config) {
+        match template::render_directory(
+            config.downloads.directory_template.as_str(),
+            meta,
+            config.downloads.title_max_length,
+        ) {
+            Ok(directory) => meta.directory = directory,
+            Err(err) => warn!(
+                subreddit = %meta.subreddit_name,
+                post_id = %meta.post_id,
+                error = %err,
+                "failed to render directory template, using the subreddit folder"
+            ),
+        }
+
+        let filename_template = config.downloads.filename_template.as_str();
+        if filename_template.trim().is_empty() {
+            if let Some(index) = meta.gallery_index {
+                meta.filename = format!("{}_{:02}_{}", meta.post_id, index, meta.filename);
             }
+            return;
+        }
+        match template::render_filename(filename_template, meta, config.downloads.title_max_length)
+        {
+            Ok(filename) => meta.filename = filename,
+            Err(err) => warn!(
+                subreddit = %meta.subreddit_name,
+                post_id = %meta.post_id,
+                error = %err,
+                "failed to render filename template, using the name from the url"
+            ),
+        }
+    }
 
-            let meta = DownloadMeta {
-                subreddit_name: data.subreddit,
-                post_link: format!("https://reddit.com{}", data.permalink),
-                image_width: image_size.0,
-                image_height: image_size.1,
-                url: data.url.clone(),
-                nsfw: data.over_18,
-                title: data.title,
-                author: data.author,
-                filename: Listing::get_filename_from_url(data.url.as_str()),
-            };
-            result.push(meta);
+    pub fn check_image_size(image_size: (u32, u32), config: &Config) -> Result<(), Filter> {
+        if !Listing::passed_aspect_ratio(image_size, config) {
+            return Err(Filter::AspectRatio);
         }
-        result
+        if !Listing::passed_mininum_size(image_size, config) {
+            return Err(Filter::MinimumSize);
+        }
+        if !Listing::passed_maximum_size(image_size, config) {
+            return Err(Filter::MaximumSize);
+        }
+        Ok(())
     }
 
     fn passed_aspect_ratio(image_size: (u32, u32), config: &Config) -> bool {
         if !config.aspect_ratio.enable {
             return true;
         }
-        let ar = config.aspect_ratio.width_aspect as f32 / config.aspect_ratio.height_aspect as f32;
-        let min_ratio = ar - config.aspect_ratio.ratio_range;
-        let max_ratio = ar + config.aspect_ratio.ratio_range;
+        // a size reddit reports wrongly can't match any ratio
+        if image_size.1 == 0 {
+            return false;
+        }
+        // invalid ratios are refused before the listing is fetched, but never accept everything
+        let bounds = match config.aspect_ratio.bounds() {
+            Ok(bounds) => bounds,
+            Err(_) => return false,
+        };
         let image_ratio = image_size.0 as f32 / image_size.1 as f32;
-        image_ratio >= min_ratio && image_ratio <= max_ratio
+        bounds
+            .iter()
+            .any(|(min, max)| image_ratio >= *min && image_ratio <= *max)
+    }
+
+    fn passed_domain(url: &str, config: &Config) -> bool {
+        let allowed = &config.downloads.allowed_domains;
+        let blocked = &config.downloads.blocked_domains;
+        if allowed.is_empty() && blocked.is_empty() {
+            return true;
+        }
+        let host = match resolver::host(url) {
+            Some(host) => host,
+            None => return allowed.is_empty(),
+        };
+        if blocked
+            .iter()
+            .any(|domain| resolver::matches_domain(host.as_str(), domain))
+        {
+            return false;
+        }
+        allowed.is_empty()
+            || allowed
+                .iter()
+                .any(|domain| resolver::matches_domain(host.as_str(), domain))
     }
 
     fn passed_mininum_size(image_size: (u32, u32), config: &Config) -> bool {
@@ -74,21 +311,39 @@ impl Listing {
             && image_size.1 >= config.minimum_size.minimum_height as u32
     }
 
+    fn passed_maximum_size(image_size: (u32, u32), config: &Config) -> bool {
+        if !config.maximum_size.enable {
+            return true;
+        }
+        let (max_width, max_height) = (
+            config.maximum_size.maximum_width as u32,
+            config.maximum_size.maximum_height as u32,
+        );
+        (max_width == 0 || image_size.0 <= max_width)
+            && (max_height == 0 || image_size.1 <= max_height)
+    }
+
+    fn is_image_url(url: &str) -> bool {
+        let filename = Listing::get_filename_from_url(url).to_lowercase();
+        [".jpg", ".jpeg", ".png", ".webp", ".gif"]
+            .iter()
+            .any(|ext| filename.ends_with(ext))
+    }
+
     fn get_filename_from_url(url: &str) -> String {
-        url.split("/").last().unwrap().split("?").take(1).collect()
+        url.split('/')
+            .next_back()
+            .unwrap()
+            .split('?')
+            .take(1)
+            .collect()
     }
 }
 
-// fn slice_from_end(s: &str, n: usize) -> Option<&str> {
-//     s.char_indices().rev().nth(n).map(|(i, _)| &s[i..])
-// }
-
 #[derive(Deserialize)]
 pub struct Data {
-    pub modhash: String,
-    pub dist: i64,
     pub children: Vec<Children>,
-    pub after: String,
+    pub after: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -100,41 +355,95 @@ pub struct Children {
 pub struct ChildrenData {
     pub subreddit: String,
     pub title: String,
-    pub post_hint: Option<String>,
-    pub created: f64,
+    pub created_utc: f64,
     pub over_18: bool,
     pub preview: Option<Preview>,
     pub id: String,
     pub author: String,
     pub permalink: String,
     pub stickied: bool,
+    #[serde(default)]
+    pub score: i64,
+    #[serde(default)]
+    pub upvote_ratio: f64,
+    pub link_flair_text: Option<String>,
     pub url: String,
     pub is_video: bool,
     pub is_gallery: Option<bool>,
+    pub gallery_data: Option<GalleryData>,
+    pub media_metadata: Option<HashMap<String, MediaMetadata>>,
 }
 
 #[derive(Deserialize)]
-pub struct MediaEmbed {}
+pub struct GalleryData {
+    pub items: Vec<GalleryItem>,
+}
 
 #[derive(Deserialize)]
-pub struct SecureMediaEmbed {}
+pub struct GalleryItem {
+    pub media_id: String,
+}
 
 #[derive(Deserialize)]
-pub struct Gildings {
-    pub gid1: Option<i64>,
-    pub gid2: Option<i64>,
+pub struct MediaMetadata {
+    pub status: String,
+    /// media kind. `Image` for still images, `AnimatedImage` for gifs.
+    pub e: Option<String>,
+    /// mime type, e.g. `image/jpg`
+    pub m: Option<String>,
+    pub s: Option<MediaSource>,
+}
+
+impl MediaMetadata {
+    /// gifs and videos in a gallery. They are not downloaded, like video posts.
+    pub fn is_animated(&self) -> bool {
+        matches!(
+            self.e.as_deref(),
+            Some("AnimatedImage") | Some("RedditVideo")
+        )
+    }
+
+    /// tuple looks like this `(width, height)`
+    pub fn get_image_size(&self) -> Option<(u32, u32)> {
+        if self.status != "valid" || self.e.as_deref() != Some("Image") {
+            return None;
+        }
+        let source = self.s.as_ref()?;
+        Some((source.x, source.y))
+    }
+
+    /// Direct url to the original image. The url in `s` points to a preview, so it's built from the media id instead.
+    pub fn get_url(&self, media_id: &str) -> Option<String> {
+        let ext = match self.m.as_deref()?.split('/').nth(1)? {
+            "jpeg" => "jpg",
+            ext => ext,
+        };
+        Some(format!("https://i.redd.it/{}.{}", media_id, ext))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MediaSource {
+    pub x: u32,
+    pub y: u32,
 }
 
 #[derive(Deserialize)]
 pub struct Preview {
     pub images: Vec<Image>,
-    pub enabled: bool,
 }
 
 impl Preview {
+    /// Link to the full size image reddit shows in the preview.
+    pub fn get_source_url(&self) -> Option<String> {
+        self.images
+            .first()
+            .map(|img| resolver::unescape(img.source.url.as_str()))
+    }
+
     /// tuple looks like this `(width, height)`
     pub fn get_image_size(&self) -> Option<(u32, u32)> {
-        if let Some(img) = self.images.get(0) {
+        if let Some(img) = self.images.first() {
             let source = &img.source;
             return Some((source.width, source.height));
         }
@@ -145,8 +454,6 @@ impl Preview {
 #[derive(Deserialize)]
 pub struct Image {
     pub source: Source,
-    pub resolutions: Vec<Resolution>,
-    pub id: String,
 }
 
 #[derive(Deserialize)]
@@ -155,10 +462,3 @@ pub struct Source {
     pub width: u32,
     pub height: u32,
 }
-
-#[derive(Deserialize)]
-pub struct Resolution {
-    pub url: String,
-    pub width: i64,
-    pub height: i64,
-}
@@ -2,16 +2,37 @@ use std::path::{Path, PathBuf};
 
 use path_absolutize::Absolutize;
 
+use crate::app::config::model::Collision;
+
+use super::format::ImageFormat;
+
+/// Extensions hosts use for images and image-like media. They are replaced, not appended to, when fixing the extension.
+pub static MEDIA_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "jpe", "jfif", "png", "gif", "gifv", "webp", "bmp",
+];
+
+#[derive(Default, Clone)]
 pub struct DownloadMeta {
+    pub post_id: String,
     pub url: String,
     pub subreddit_name: String,
-    pub image_height: u32,
-    pub image_width: u32,
+    /// name of the source the post was listed from, see [`crate::app::config::model::Source::name`].
+    pub source: String,
+    /// `(width, height)`. `None` when the post has no preview. It's set to the real size after download when verify_dimensions is enabled.
+    pub image_size: Option<(u32, u32)>,
     pub post_link: String,
     pub nsfw: bool,
     pub filename: String,
+    /// folder of the file relative to the download path, with `/` between folders. See [`super::template::render_directory`].
+    pub directory: String,
     pub title: String,
     pub author: String,
+    /// unix timestamp of the post
+    pub created_utc: f64,
+    /// 1 based position in a gallery post. `None` for single image posts.
+    pub gallery_index: Option<usize>,
+    /// id of the imgur album the post links to. The download is replaced with the album's images when an imgur client id is set.
+    pub imgur_album: Option<String>,
 }
 
 impl DownloadMeta {
@@ -20,10 +41,86 @@ impl DownloadMeta {
             .unwrap()
             .to_string();
         Path::new(b.as_str())
-            .join(&self.subreddit_name)
+            .join(self.directory.split('/').collect::<PathBuf>())
             .join(&self.filename)
             .absolutize()
             .unwrap()
             .to_path_buf()
     }
+
+    /// Looks for the files of this download. With `any_extension`, files with the same name but another image extension also count,
+    /// since the extension may have been changed to match the content.
+    pub fn get_existing_file_locations<P: AsRef<Path>>(
+        &self,
+        base_location: P,
+        any_extension: bool,
+    ) -> Vec<PathBuf> {
+        let loc = self.get_file_location(base_location);
+        let mut existing = Vec::new();
+        if loc.exists() {
+            existing.push(loc.clone());
+        }
+        if !any_extension {
+            return existing;
+        }
+        let stem = self.get_filename_stem();
+        for format in [
+            ImageFormat::Jpeg,
+            ImageFormat::Png,
+            ImageFormat::Gif,
+            ImageFormat::Webp,
+        ] {
+            let variant = loc.with_file_name(format!("{}.{}", stem, format.extension()));
+            if variant != loc && variant.exists() {
+                existing.push(variant);
+            }
+        }
+        existing
+    }
+
+    /// The file's location without its image extension. With extensions from the content,
+    /// the file may end up with any image extension, so names are compared this way.
+    pub fn get_file_stem_location<P: AsRef<Path>>(&self, base_location: P) -> PathBuf {
+        let loc = self.get_file_location(base_location);
+        loc.with_file_name(self.get_filename_stem())
+    }
+
+    /// Makes the filename extension match `format`. Extensions that already match, like `.jpeg` for jpeg, are kept.
+    pub fn set_extension(&mut self, format: ImageFormat) {
+        let ext = self.filename.rsplit_once('.').map(|(_, ext)| ext);
+        if ext.and_then(ImageFormat::from_extension) == Some(format) {
+            return;
+        }
+        self.filename = format!("{}.{}", self.get_filename_stem(), format.extension());
+    }
+
+    fn get_filename_stem(&self) -> &str {
+        DownloadMeta::split_filename(self.filename.as_str()).0
+    }
+
+    /// Name to try on the `attempt`th collision of `filename`, starting from 1. `None` when the policy does not rename.
+    pub fn collision_filename(
+        filename: &str,
+        post_id: &str,
+        policy: Collision,
+        attempt: usize,
+    ) -> Option<String> {
+        let (stem, ext) = DownloadMeta::split_filename(filename);
+        match policy {
+            Collision::Skip | Collision::Overwrite => None,
+            Collision::Suffix => Some(format!("{}_{}{}", stem, attempt, ext)),
+            Collision::PrefixWithId if attempt == 1 => Some(format!("{}_{}", post_id, filename)),
+            Collision::PrefixWithId => Some(format!("{}_{}_{}{}", post_id, stem, attempt - 1, ext)),
+        }
+    }
+
+    /// Splits the filename into the name and the image extension with its dot. The extension is empty when there's none.
+    fn split_filename(filename: &str) -> (&str, &str) {
+        match filename.rsplit_once('.') {
+            Some((stem, ext)) if MEDIA_EXTENSIONS.contains(&ext.to_lowercase().as_str()) => {
+                (stem, &filename[stem.len()..])
+            }
+            _ => (filename, ""),
+        }
+    }
 }
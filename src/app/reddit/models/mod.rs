@@ -1,2 +1,7 @@
+pub mod filter;
+pub mod format;
+pub mod imgur;
 pub mod listing;
 pub mod meta;
+pub mod resolver;
+pub mod template;
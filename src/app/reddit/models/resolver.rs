@@ -0,0 +1,107 @@
+/// What a post's link points to, as far as can be told from the link alone.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resolved {
+    /// a direct link to an image.
+    Image(String),
+    /// an imgur album or gallery post with the given id. Its images are listed by the imgur api.
+    ImgurAlbum(String),
+    Video,
+    /// a page this app can't read images from, like a flickr page or a reddit gallery link.
+    Unknown,
+}
+
+/// Turns page links of known hosts into direct image links.
+pub fn resolve(url: &str) -> Resolved {
+    let host = match host(url) {
+        Some(host) => host,
+        None => return Resolved::Unknown,
+    };
+    let path = url_path(url);
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    let ext = segments
+        .last()
+        .and_then(|last| last.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_lowercase());
+
+    match host.as_str() {
+        "i.redd.it" => Resolved::Image(url.to_string()),
+        "v.redd.it" => Resolved::Video,
+        "i.imgur.com" => match ext.as_deref() {
+            Some("gifv") | Some("mp4") | Some("webm") => Resolved::Video,
+            _ => Resolved::Image(url.to_string()),
+        },
+        "imgur.com" | "m.imgur.com" => match segments.as_slice() {
+            ["a", id] | ["gallery", id] => Resolved::ImgurAlbum(strip_slug(id).to_string()),
+            [name] => match name.rsplit_once('.') {
+                Some((_, "gifv")) | Some((_, "mp4")) => Resolved::Video,
+                Some((id, ext)) => Resolved::Image(format!("https://i.imgur.com/{}.{}", id, ext)),
+                // imgur serves the image for any image extension, the real one is fixed after download
+                None => Resolved::Image(format!("https://i.imgur.com/{}.jpg", name)),
+            },
+            _ => Resolved::Unknown,
+        },
+        _ if is_image_extension(ext.as_deref()) => Resolved::Image(url.to_string()),
+        _ => Resolved::Unknown,
+    }
+}
+
+/// Lowercase host of the url without `www.`, port and credentials.
+pub fn host(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map(|(_, rest)| rest)?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?;
+    if host.is_empty() {
+        return None;
+    }
+    let host = host.to_lowercase();
+    Some(
+        host.strip_prefix("www.")
+            .map(str::to_string)
+            .unwrap_or(host),
+    )
+}
+
+/// Whether `host` is `domain` or one of its subdomains, e.g. `i.imgur.com` matches `imgur.com`.
+pub fn matches_domain(host: &str, domain: &str) -> bool {
+    let domain = domain.trim().trim_start_matches("www.").to_lowercase();
+    !domain.is_empty() && (host == domain || host.ends_with(format!(".{}", domain).as_str()))
+}
+
+/// Reddit escapes `&` in preview urls of the json api, which breaks their signature.
+pub fn unescape(url: &str) -> String {
+    url.replace("&amp;", "&")
+}
+
+/// Percent-encodes a query parameter value.
+pub fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn url_path(url: &str) -> &str {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    rest.find('/').map(|i| &rest[i..]).unwrap_or_default()
+}
+
+/// Newer imgur links put the title before the id, e.g. `/gallery/my-wallpaper-AbC12`.
+fn strip_slug(id: &str) -> &str {
+    id.rsplit('-').next().unwrap_or(id)
+}
+
+fn is_image_extension(ext: Option<&str>) -> bool {
+    matches!(
+        ext,
+        Some("jpg") | Some("jpeg") | Some("png") | Some("webp") | Some("gif")
+    )
+}
@@ -0,0 +1,252 @@
+use chrono::{format::Item, format::StrftimeItems, DateTime};
+
+use super::meta::{DownloadMeta, MEDIA_EXTENSIONS};
+
+/// Characters that are not allowed in file names on at least one of the supported platforms.
+static RESERVED_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Most file systems limit a file name to 255 bytes. Some room is left for the `.part` suffix and collision suffixes.
+const MAX_FILENAME_BYTES: usize = 200;
+
+/// Renders `{name}` and `{name:format}` placeholders using `lookup`. `{{` and `}}` are literal braces.
+/// Fails on unknown placeholders, i.e. when `lookup` returns `None`.
+pub fn render<F>(template: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str, Option<&str>) -> Option<String>,
+{
+    let mut result = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(format!("unclosed placeholder in '{}'", template)),
+                    }
+                }
+                let (name, format) = match placeholder.split_once(':') {
+                    Some((name, format)) => (name, Some(format)),
+                    None => (placeholder.as_str(), None),
+                };
+                let value = lookup(name.trim(), format)
+                    .ok_or_else(|| format!("unknown placeholder '{{{}}}'", placeholder))?;
+                result.push_str(value.as_str());
+            }
+            '}' => return Err(format!("unmatched '}}' in '{}'", template)),
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+/// Renders the filename template for a download. Gallery items get their position appended when the template has no `{index}`,
+/// so they don't overwrite each other.
+pub fn render_filename(
+    template: &str,
+    download: &DownloadMeta,
+    title_max_length: usize,
+) -> Result<String, String> {
+    let (url_stem, url_ext) = match download.filename.rsplit_once('.') {
+        Some((stem, ext)) if MEDIA_EXTENSIONS.contains(&ext.to_lowercase().as_str()) => {
+            (stem.to_string(), ext.to_string())
+        }
+        _ => (download.filename.clone(), String::new()),
+    };
+    let rendered = render(template, |name, format| {
+        download_value(download, name, format, title_max_length).or_else(|| match name {
+            "name" if format.is_none() => Some(sanitize(url_stem.as_str())),
+            "ext" if format.is_none() => Some(url_ext.clone()),
+            _ => None,
+        })
+    })?;
+    let mut filename = rendered.trim().trim_end_matches('.').to_string();
+
+    if let Some(index) = download.gallery_index {
+        if !template.contains("{index}") {
+            filename = match filename.rsplit_once('.') {
+                Some((stem, ext)) if MEDIA_EXTENSIONS.contains(&ext.to_lowercase().as_str()) => {
+                    format!("{}_{:02}.{}", stem, index, ext)
+                }
+                _ => format!("{}_{:02}", filename, index),
+            };
+        }
+    }
+
+    if filename.is_empty() {
+        return Err(format!(
+            "template '{}' rendered an empty filename",
+            template
+        ));
+    }
+    Ok(truncate_filename(filename))
+}
+
+/// Renders the directory template for a download into a relative path, with `/` between folders.
+/// Empty, `.` and `..` folders are dropped, so the result always stays inside the download path.
+pub fn render_directory(
+    template: &str,
+    download: &DownloadMeta,
+    title_max_length: usize,
+) -> Result<String, String> {
+    let rendered = render(template, |name, format| {
+        download_value(download, name, format, title_max_length).or_else(|| match (name, format) {
+            ("year", None) => format_timestamp(download.created_utc, "%Y"),
+            ("month", None) => format_timestamp(download.created_utc, "%m"),
+            ("day", None) => format_timestamp(download.created_utc, "%d"),
+            ("orientation", None) => Some(orientation(download).to_string()),
+            ("resolution_bucket", None) => Some(resolution_bucket(download).to_string()),
+            _ => None,
+        })
+    })?;
+    Ok(rendered
+        .split(['/', '\\'])
+        .map(str::trim)
+        .filter(|folder| !folder.is_empty() && *folder != "." && *folder != "..")
+        .collect::<Vec<_>>()
+        .join("/"))
+}
+
+/// `unknown` when the size is not known before download, see [`DownloadMeta::image_size`].
+fn orientation(download: &DownloadMeta) -> &'static str {
+    let (width, height) = match download.image_size {
+        Some(image_size) => image_size,
+        None => return "unknown",
+    };
+    if width > height {
+        "landscape"
+    } else if width < height {
+        "portrait"
+    } else {
+        "square"
+    }
+}
+
+/// Groups images by their longer side, so portrait images land in the same bucket as their landscape counterparts.
+fn resolution_bucket(download: &DownloadMeta) -> &'static str {
+    let (width, height) = match download.image_size {
+        Some(image_size) => image_size,
+        None => return "unknown",
+    };
+    match width.max(height) {
+        size if size >= 7680 => "8k",
+        size if size >= 5120 => "5k",
+        size if size >= 3840 => "4k",
+        size if size >= 2560 => "1440p",
+        size if size >= 1920 => "1080p",
+        size if size >= 1280 => "720p",
+        _ => "sd",
+    }
+}
+
+/// Values shared by every template that describes a download.
+pub fn download_value(
+    download: &DownloadMeta,
+    name: &str,
+    format: Option<&str>,
+    title_max_length: usize,
+) -> Option<String> {
+    match (name, format) {
+        ("subreddit", None) => Some(sanitize(download.subreddit_name.as_str())),
+        ("source", None) => Some(sanitize(download.source.as_str())),
+        ("id", None) => Some(sanitize(download.post_id.as_str())),
+        ("title", None) => Some(truncate_chars(
+            sanitize(download.title.as_str()),
+            title_max_length,
+        )),
+        ("author", None) => Some(sanitize(download.author.as_str())),
+        ("width", None) => Some(
+            download
+                .image_size
+                .map(|(width, _)| width.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+        ),
+        ("height", None) => Some(
+            download
+                .image_size
+                .map(|(_, height)| height.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+        ),
+        ("index", None) => Some(
+            download
+                .gallery_index
+                .map(|index| format!("{:02}", index))
+                .unwrap_or_default(),
+        ),
+        ("created", format) => format_timestamp(download.created_utc, format.unwrap_or("%Y-%m-%d")),
+        _ => None,
+    }
+}
+
+/// Formats a unix timestamp in UTC with a strftime format. Returns `None` for invalid formats.
+fn format_timestamp(timestamp: f64, format: &str) -> Option<String> {
+    let items = StrftimeItems::new(format).collect::<Vec<_>>();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return None;
+    }
+    let created = DateTime::from_timestamp(timestamp as i64, 0)?;
+    Some(sanitize(
+        created
+            .format_with_items(items.into_iter())
+            .to_string()
+            .as_str(),
+    ))
+}
+
+/// Replaces characters that are not allowed in file names, and collapses whitespace.
+pub fn sanitize(value: &str) -> String {
+    let replaced = value
+        .chars()
+        .map(|c| {
+            if RESERVED_CHARS.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+    replaced
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_matches('.')
+        .to_string()
+}
+
+fn truncate_chars(value: String, max: usize) -> String {
+    if max == 0 || value.chars().count() <= max {
+        return value;
+    }
+    value
+        .chars()
+        .take(max)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// Shortens the name part of the filename to fit [`MAX_FILENAME_BYTES`], keeping the extension.
+fn truncate_filename(filename: String) -> String {
+    if filename.len() <= MAX_FILENAME_BYTES {
+        return filename;
+    }
+    let (stem, ext) = match filename.rsplit_once('.') {
+        Some((stem, ext)) if ext.len() < 10 => (stem.to_string(), format!(".{}", ext)),
+        _ => (filename.clone(), String::new()),
+    };
+    let mut stem = stem;
+    while stem.len() + ext.len() > MAX_FILENAME_BYTES {
+        stem.pop();
+    }
+    format!("{}{}", stem.trim_end(), ext)
+}
@@ -0,0 +1,364 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error, Result};
+use serde::Deserialize;
+use tracing::{debug, info};
+use ureq::Agent;
+
+use crate::app::config::model::Config;
+
+pub static TOKEN_URL: &str = "https://www.reddit.com/api/v1/access_token";
+/// Authenticated requests go to this host instead of `reddit.com`.
+pub static API_URL: &str = "https://oauth.reddit.com";
+
+/// Grant for apps without a secret that don't act as a user. Reddit asks for a device id, which may be this fixed value.
+static INSTALLED_CLIENT_GRANT: &str = "https://oauth.reddit.com/grants/installed_client";
+static DEVICE_ID: &str = "DO_NOT_TRACK_THIS_DEVICE";
+
+/// Tokens are refreshed this long before they expire, so a token doesn't expire in the middle of a run's requests.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Fetches and caches the bearer token of a reddit app. Safe to share between threads.
+pub struct OAuth {
+    agent: Agent,
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    username: String,
+    password: String,
+    token: Mutex<Option<Token>>,
+}
+
+struct Token {
+    access_token: String,
+    expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    access_token: String,
+    #[serde(default)]
+    expires_in: u64,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl OAuth {
+    /// Returns `None` when no reddit client id is configured in `[advanced]`.
+    pub fn from_config(agent: Agent, config: &Config) -> Option<OAuth> {
+        OAuth::with_token_url(agent, config, TOKEN_URL)
+    }
+
+    pub fn with_token_url(agent: Agent, config: &Config, token_url: &str) -> Option<OAuth> {
+        let advanced = &config.advanced;
+        if advanced.reddit_client_id.trim().is_empty() {
+            return None;
+        }
+        Some(OAuth {
+            agent,
+            token_url: token_url.to_string(),
+            client_id: advanced.reddit_client_id.trim().to_string(),
+            client_secret: advanced.reddit_client_secret.trim().to_string(),
+            username: advanced.reddit_username.trim().to_string(),
+            password: advanced.reddit_password.clone(),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// Returns the value of the `Authorization` header. A new token is fetched when there is none yet,
+    /// or when the current one is about to expire.
+    pub fn authorization(&self) -> Result<String> {
+        let mut token = self.token.lock().unwrap();
+        let valid = token
+            .as_ref()
+            .map(|t| Instant::now() + EXPIRY_MARGIN < t.expires_at)
+            .unwrap_or(false);
+        if !valid {
+            *token = Some(self.fetch_token()?);
+        }
+        Ok(format!(
+            "bearer {}",
+            token
+                .as_ref()
+                .map(|t| t.access_token.as_str())
+                .unwrap_or_default()
+        ))
+    }
+
+    /// Drops the cached token, e.g. after reddit rejected it, so the next request fetches a new one.
+    pub fn invalidate(&self) {
+        *self.token.lock().unwrap() = None;
+    }
+
+    fn fetch_token(&self) -> Result<Token> {
+        let form: Vec<(&str, &str)> = if !self.username.is_empty() {
+            // script app, acting as the account that registered it
+            vec![
+                ("grant_type", "password"),
+                ("username", self.username.as_str()),
+                ("password", self.password.as_str()),
+            ]
+        } else if !self.client_secret.is_empty() {
+            vec![("grant_type", "client_credentials")]
+        } else {
+            vec![
+                ("grant_type", INSTALLED_CLIENT_GRANT),
+                ("device_id", DEVICE_ID),
+            ]
+        };
+        let grant_type = form[0].1;
+        info!(url = %self.token_url, grant_type, "fetching reddit access token");
+        let credentials = base64::encode(format!("{}:{}", self.client_id, self.client_secret));
+        let response = self
+            .agent
+            .post(self.token_url.as_str())
+            .set("Authorization", format!("Basic {}", credentials).as_str())
+            .send_form(form.as_slice())
+            .map_err(Box::new)
+            .with_context(|| format!("failed to get reddit access token from {}", self.token_url))?
+            .into_json::<TokenResponse>()
+            .with_context(|| {
+                format!(
+                    "failed to parse response body into json from {}",
+                    self.token_url
+                )
+            })?;
+        // reddit answers wrong credentials of script apps with 200 and an error in the body
+        if response.access_token.is_empty() {
+            return Err(Error::msg(format!(
+                "reddit refused to give an access token: {}",
+                response
+                    .error
+                    .unwrap_or_else(|| "no token in response".to_string())
+            )));
+        }
+        debug!(
+            expires_in = response.expires_in,
+            "fetched reddit access token"
+        );
+        Ok(Token {
+            access_token: response.access_token,
+            expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::reddit::agent::new_agent;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// A request received by the mock token endpoint.
+    struct Received {
+        authorization: String,
+        body: String,
+    }
+
+    /// Serves the given responses to the token requests in order, one per connection,
+    /// and records every request. Returns the token url.
+    fn mock_token_endpoint(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/api/v1/access_token",
+            listener.local_addr().unwrap()
+        );
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let recorder = received.clone();
+        thread::spawn(move || {
+            for (status, body) in responses.into_iter() {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut authorization = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(": ") {
+                        match name.to_lowercase().as_str() {
+                            "authorization" => authorization = value.to_string(),
+                            "content-length" => length = value.parse().unwrap(),
+                            _ => {}
+                        }
+                    }
+                }
+                let mut request_body = vec![0; length];
+                reader.read_exact(&mut request_body).unwrap();
+                recorder.lock().unwrap().push(Received {
+                    authorization,
+                    body: String::from_utf8(request_body).unwrap(),
+                });
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn config(client_id: &str, client_secret: &str, username: &str, password: &str) -> Config {
+        let mut config: Config =
+            toml::from_str(crate::app::config::default::DEFAULT_CONFIG).unwrap();
+        config.advanced.reddit_client_id = client_id.to_string();
+        config.advanced.reddit_client_secret = client_secret.to_string();
+        config.advanced.reddit_username = username.to_string();
+        config.advanced.reddit_password = password.to_string();
+        config
+    }
+
+    fn oauth(config: &Config, token_url: &str) -> OAuth {
+        OAuth::with_token_url(new_agent(config), config, token_url).unwrap()
+    }
+
+    #[test]
+    fn disabled_without_client_id() {
+        let config = config("", "secret", "", "");
+        assert!(OAuth::from_config(new_agent(&config), &config).is_none());
+    }
+
+    #[test]
+    fn app_only_token_is_fetched_once() {
+        let (url, received) = mock_token_endpoint(vec![(
+            200,
+            r#"{"access_token": "abc", "token_type": "bearer", "expires_in": 86400, "scope": "*"}"#,
+        )]);
+        let config = config("id", "secret", "", "");
+        let oauth = oauth(&config, url.as_str());
+
+        assert_eq!(oauth.authorization().unwrap(), "bearer abc");
+        assert_eq!(oauth.authorization().unwrap(), "bearer abc");
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].authorization, "Basic aWQ6c2VjcmV0");
+        assert_eq!(received[0].body, "grant_type=client_credentials");
+    }
+
+    #[test]
+    fn script_app_logs_in_with_password() {
+        let (url, received) = mock_token_endpoint(vec![(
+            200,
+            r#"{"access_token": "abc", "token_type": "bearer", "expires_in": 86400}"#,
+        )]);
+        let config = config("id", "secret", "someone", "p@ss word");
+        let oauth = oauth(&config, url.as_str());
+
+        assert_eq!(oauth.authorization().unwrap(), "bearer abc");
+
+        let received = received.lock().unwrap();
+        assert_eq!(
+            received[0].body,
+            "grant_type=password&username=someone&password=p%40ss+word"
+        );
+    }
+
+    #[test]
+    fn password_is_sent_as_configured() {
+        let (url, received) = mock_token_endpoint(vec![(
+            200,
+            r#"{"access_token": "abc", "token_type": "bearer", "expires_in": 86400}"#,
+        )]);
+        let config = config("id", "secret", "someone", " secret ");
+        let oauth = oauth(&config, url.as_str());
+
+        assert_eq!(oauth.authorization().unwrap(), "bearer abc");
+
+        let received = received.lock().unwrap();
+        assert_eq!(
+            received[0].body,
+            "grant_type=password&username=someone&password=+secret+"
+        );
+    }
+
+    #[test]
+    fn installed_app_uses_device_id() {
+        let (url, received) = mock_token_endpoint(vec![(
+            200,
+            r#"{"access_token": "abc", "token_type": "bearer", "expires_in": 86400}"#,
+        )]);
+        let config = config("id", "", "", "");
+        let oauth = oauth(&config, url.as_str());
+
+        assert_eq!(oauth.authorization().unwrap(), "bearer abc");
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].authorization, "Basic aWQ6");
+        assert!(received[0]
+            .body
+            .contains("device_id=DO_NOT_TRACK_THIS_DEVICE"));
+    }
+
+    #[test]
+    fn expiring_token_is_refreshed() {
+        let (url, received) = mock_token_endpoint(vec![
+            (
+                200,
+                r#"{"access_token": "first", "token_type": "bearer", "expires_in": 30}"#,
+            ),
+            (
+                200,
+                r#"{"access_token": "second", "token_type": "bearer", "expires_in": 86400}"#,
+            ),
+        ]);
+        let config = config("id", "secret", "", "");
+        let oauth = oauth(&config, url.as_str());
+
+        assert_eq!(oauth.authorization().unwrap(), "bearer first");
+        // expires within the margin, so it's replaced right away
+        assert_eq!(oauth.authorization().unwrap(), "bearer second");
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalidated_token_is_fetched_again() {
+        let (url, received) = mock_token_endpoint(vec![
+            (
+                200,
+                r#"{"access_token": "first", "token_type": "bearer", "expires_in": 86400}"#,
+            ),
+            (
+                200,
+                r#"{"access_token": "second", "token_type": "bearer", "expires_in": 86400}"#,
+            ),
+        ]);
+        let config = config("id", "secret", "", "");
+        let oauth = oauth(&config, url.as_str());
+
+        assert_eq!(oauth.authorization().unwrap(), "bearer first");
+        oauth.invalidate();
+        assert_eq!(oauth.authorization().unwrap(), "bearer second");
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn refused_credentials_are_an_error() {
+        let (url, _) = mock_token_endpoint(vec![
+            (200, r#"{"error": "invalid_grant"}"#),
+            (401, r#"{"message": "Unauthorized", "error": 401}"#),
+        ]);
+        let config = config("id", "secret", "someone", "wrong");
+        let oauth = oauth(&config, url.as_str());
+
+        let err = format!("{:#}", oauth.authorization().unwrap_err());
+        assert!(err.contains("invalid_grant"), "{}", err);
+        let err = format!("{:#}", oauth.authorization().unwrap_err());
+        assert!(err.contains("401"), "{}", err);
+    }
+}
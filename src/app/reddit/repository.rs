@@ -1,118 +1,919 @@
-use std::{fs, fs::File, path::Path};
+use std::{
+    fs,
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Error, Result};
 use path_absolutize::Absolutize;
-use retry::delay::Fixed;
-use retry::retry;
+use tracing::{debug, error, info, warn};
 use ureq::{Agent, Response};
 
-use crate::app::config::{model::Config, sort::Sort};
+use crate::app::config::model::{Collision, Config, ExtensionSource, Source, SourceKind};
 
+use super::models::filter::{Collected, Filter, PostFilter, Rejected};
+use super::models::format::ImageFormat;
+use super::models::imgur::ImgurAlbum;
 use super::models::listing::Listing;
 use super::models::meta::DownloadMeta;
-use std::io;
+use super::models::resolver::{self, Resolved};
+use super::oauth::{self, OAuth};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+/// Partial downloads not resumed for this long are removed, see [`Repository::remove_stale_temp_files`].
+const STALE_TEMP_AGE: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+/// Requests to the reddit api are tried this many times.
+const API_TRIES: usize = 4;
+const API_RETRY_DELAY: Duration = Duration::from_millis(200);
+/// Wait after a 429 that doesn't say how long to wait.
+const RATE_LIMIT_WAIT: Duration = Duration::from_secs(10);
+/// Longer waits asked for by reddit are cut to this, so a run doesn't hang for the rest of the rate limit window.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Clone)]
 pub struct Repository {
     agent: Agent,
     config: Config,
+    /// `None` when no reddit app is configured, and listings are fetched from the public api.
+    oauth: Option<Arc<OAuth>>,
 }
 
 // There should be no concurrency in repository level. concurrency should be done in service level.
 impl Repository {
-    pub fn new(agent: Agent, config: Config) -> Repository {
-        Repository { agent, config }
+    /// `oauth` is the reddit app to fetch listings with, see [`new_oauth`](super::agent::new_oauth).
+    pub fn new(agent: Agent, oauth: Option<Arc<OAuth>>, config: Config) -> Repository {
+        Repository {
+            agent,
+            config,
+            oauth,
+        }
     }
 
-    pub fn get_listing(&self, subreddit: &str, sort: Sort) -> Result<Vec<DownloadMeta>> {
-        let listing_url = format!("https://reddit.com/r/{}/{}.json?limit=100", subreddit, sort);
-        println!("[{}] fetching listing from {}", subreddit, listing_url);
-        let listing = retry(Fixed::from_millis(200).take(3), || {
-            self.agent.get(listing_url.as_str()).call()
-        })
-        .with_context(|| format!("[{}] failed to get listing from {}", subreddit, listing_url))?
-        .into_json::<Listing>()
-        .with_context(|| {
-            format!(
-                "[{}] failed to parse response body into json from {}",
-                subreddit, listing_url
+    /// `config` is the config that applies to the source, see [`Config::source_configs`].
+    pub fn get_listing(&self, source: &Source, config: &Config) -> Result<Collected> {
+        let name = source.name();
+        source
+            .check()
+            .map_err(Error::msg)
+            .with_context(|| format!("[{}] invalid source", name))?;
+        let max_posts = config.downloads.max_posts;
+        let mut result = Collected::default();
+        let mut after: Option<String> = None;
+        let post_filter = PostFilter::new(&config.filters)
+            .with_context(|| format!("[{}] invalid filters", name))?;
+        if config.aspect_ratio.enable {
+            config
+                .aspect_ratio
+                .bounds()
+                .map_err(Error::msg)
+                .with_context(|| format!("[{}] invalid aspect_ratio", name))?;
+        }
+        for page in 0..config.downloads.pages.max(1) {
+            let limit = if max_posts > 0 {
+                max_posts.saturating_sub(result.posts).min(100)
+            } else {
+                100
+            };
+            let mut listing_url = self.get_listing_url(source, config, limit);
+            if let Some(after) = after.as_ref() {
+                listing_url.push_str(format!("&after={}", after).as_str());
+            }
+            let listing = match self.fetch_listing(name.as_str(), listing_url.as_str()) {
+                Ok(listing) => listing,
+                // keep what the previous pages already found
+                Err(err) if page > 0 => {
+                    error!(source = %name, url = %listing_url, error = %format!("{:#}", err), "failed to get listing page, stopped fetching more pages");
+                    result.failed_pages += 1;
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            after = listing.data.after.clone();
+            let mut collected = listing.into_download_metas(name.as_str(), config, &post_filter);
+            if !self.config.advanced.imgur_client_id.trim().is_empty() {
+                self.expand_imgur_albums(&mut collected, config);
+            }
+            result.merge(collected);
+            if after.is_none() || (max_posts > 0 && result.posts >= max_posts) {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Search listings take `hot`, `new` and `top` sorts, user listings every sort but `rising`.
+    /// Reddit falls back to its default sort for the others.
+    fn get_listing_url(&self, source: &Source, config: &Config, limit: usize) -> String {
+        let sort = config.downloads.sort;
+        let api = self.get_api_url();
+        let mut listing_url = match source.kind {
+            SourceKind::Subreddit => format!(
+                "{}/r/{}/{}.json?limit={}",
+                api,
+                source.subreddit.trim(),
+                sort,
+                limit
+            ),
+            SourceKind::Multireddit => format!(
+                "{}/r/{}/{}.json?limit={}",
+                api,
+                source
+                    .subreddits
+                    .iter()
+                    .map(|sub| sub.trim())
+                    .collect::<Vec<_>>()
+                    .join("+"),
+                sort,
+                limit
+            ),
+            SourceKind::Search if source.subreddit.trim().is_empty() => format!(
+                "{}/search.json?q={}&sort={}&limit={}",
+                api,
+                resolver::encode(source.query.trim()),
+                sort,
+                limit
+            ),
+            SourceKind::Search => format!(
+                "{}/r/{}/search.json?q={}&restrict_sr=1&sort={}&limit={}",
+                api,
+                source.subreddit.trim(),
+                resolver::encode(source.query.trim()),
+                sort,
+                limit
+            ),
+            SourceKind::User => format!(
+                "{}/user/{}/submitted.json?sort={}&limit={}",
+                api,
+                source.user.trim(),
+                sort,
+                limit
+            ),
+            // a listing of the single post, unlike the permalink which also lists the comments.
+            SourceKind::Post => {
+                return format!(
+                    "{}/by_id/t3_{}.json",
+                    api,
+                    source.post_id().unwrap_or_default()
+                )
+            }
+        };
+        if sort.has_time_window() {
+            listing_url.push_str(format!("&t={}", config.downloads.time_window).as_str());
+        }
+        listing_url
+    }
+
+    /// Replaces downloads of imgur album posts with the album's images. When the album can't be fetched,
+    /// the image reddit shows in the preview is downloaded instead, if there is one.
+    fn expand_imgur_albums(&self, collected: &mut Collected, config: &Config) {
+        let downloads = std::mem::take(&mut collected.downloads);
+        for download in downloads.into_iter() {
+            let album_id = match download.imgur_album.as_ref() {
+                Some(album_id) => album_id,
+                None => {
+                    collected.downloads.push(download);
+                    continue;
+                }
+            };
+            match self.fetch_imgur_album(download.subreddit_name.as_str(), album_id.as_str()) {
+                Ok(album) => {
+                    for result in Listing::imgur_album_download_metas(&download, album, config) {
+                        match result {
+                            Ok(meta) => collected.downloads.push(meta),
+                            Err((subreddit, post_id, filter)) => {
+                                collected.reject(subreddit.as_str(), post_id.as_str(), filter)
+                            }
+                        }
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        subreddit = %download.subreddit_name,
+                        post_id = %download.post_id,
+                        album = %album_id,
+                        error = %format!("{:#}", err),
+                        "failed to get imgur album"
+                    );
+                    if let Resolved::Image(_) = resolver::resolve(download.url.as_str()) {
+                        collected.downloads.push(DownloadMeta {
+                            imgur_album: None,
+                            ..download
+                        });
+                    } else {
+                        collected.reject(
+                            download.subreddit_name.as_str(),
+                            download.post_id.as_str(),
+                            Filter::NoImage,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn fetch_imgur_album(&self, subreddit: &str, album_id: &str) -> Result<ImgurAlbum> {
+        let url = format!("https://api.imgur.com/3/album/{}/images", album_id);
+        info!(subreddit, url = %url, "fetching imgur album");
+        let album = self
+            .agent
+            .get(url.as_str())
+            .set(
+                "Authorization",
+                format!("Client-ID {}", self.config.advanced.imgur_client_id.trim()).as_str(),
             )
-        })?;
-        Ok(listing.into_download_metas(&self.config))
+            .call()
+            .map_err(Box::new)
+            .with_context(|| format!("[{}] failed to get imgur album from {}", subreddit, url))?
+            .into_json::<ImgurAlbum>()
+            .with_context(|| {
+                format!(
+                    "[{}] failed to parse response body into json from {}",
+                    subreddit, url
+                )
+            })?;
+        if !album.success {
+            return Err(Error::msg(format!(
+                "[{}] imgur failed to list the album {}",
+                subreddit, album_id
+            )));
+        }
+        Ok(album)
     }
 
-    pub fn download_image(&self, download: &DownloadMeta) -> Result<Response> {
-        let response = retry(Fixed::from_millis(200).take(3), || {
-            self.agent.get(download.url.as_str()).call()
+    fn get_api_url(&self) -> &'static str {
+        if self.oauth.is_some() {
+            oauth::API_URL
+        } else {
+            "https://reddit.com"
+        }
+    }
+
+    fn fetch_listing(&self, source: &str, listing_url: &str) -> Result<Listing> {
+        info!(source, url = listing_url, "fetching listing");
+        let listing = self
+            .get_api(listing_url)
+            .with_context(|| format!("[{}] failed to get listing from {}", source, listing_url))?
+            .into_json::<Listing>()
+            .with_context(|| {
+                format!(
+                    "[{}] failed to parse response body into json from {}",
+                    source, listing_url
+                )
+            })?;
+        Ok(listing)
+    }
+
+    /// Requests to the reddit api carry the bearer token when a reddit app is configured.
+    /// A token reddit rejects is dropped, so the next try fetches a new one.
+    /// When reddit answers 429, the next try waits as long as reddit asks for.
+    fn get_api(&self, url: &str) -> Result<Response> {
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            let err = match self.call_api(url) {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            if tries >= API_TRIES {
+                return Err(err);
+            }
+            let wait = match err.downcast_ref::<ureq::Error>() {
+                Some(ureq::Error::Status(429, response)) => {
+                    let wait = rate_limit_wait(response);
+                    warn!(
+                        url,
+                        wait_secs = wait.as_secs(),
+                        "rate limited by reddit, waiting before retrying"
+                    );
+                    wait
+                }
+                _ => API_RETRY_DELAY,
+            };
+            thread::sleep(wait);
+        }
+    }
+
+    fn call_api(&self, url: &str) -> Result<Response> {
+        let mut request = self.agent.get(url);
+        if let Some(oauth) = self.oauth.as_ref() {
+            request = request.set("Authorization", oauth.authorization()?.as_str());
+        }
+        request.call().map_err(|err| {
+            if let (ureq::Error::Status(401, _), Some(oauth)) = (&err, self.oauth.as_ref()) {
+                oauth.invalidate();
+            }
+            Error::new(err)
         })
-        .with_context(|| {
-            format!(
-                "[{}] failed to open connection to {}",
-                download.subreddit_name, download.url
-            )
-        })?;
-        Ok(response)
     }
 
-    pub fn store_image(&self, response: Response, download: &DownloadMeta) -> Result<()> {
+    /// Downloads the image into a temporary file next to the final location, and moves it into place
+    /// only after the whole body is read. A failed download leaves no file on the final location, so it's retried on the next run.
+    ///
+    /// When the server supports range requests, the partial file is kept and later attempts continue from where it stopped.
+    /// The partial file is discarded when the image on the server has changed since.
+    ///
+    /// Responses that are not images are rejected with a [`Rejected`] error when validation is enabled.
+    ///
+    /// When the extension comes from the content, `download.filename` is updated to match the image's format.
+    ///
+    /// Returns the size of the image in bytes.
+    pub fn download_image(&self, download: &mut DownloadMeta) -> Result<u64> {
         let full_loc = download.get_file_location(self.config.get_download_path());
-        let mut f = File::create(full_loc.clone()).with_context(|| {
-            format!(
-                "[{}] failed creating file on {}",
-                download.subreddit_name,
-                full_loc.display()
-            )
-        })?;
+        let temp_loc = Repository::get_temp_location(full_loc.as_path());
+        let validator_loc = Repository::get_validator_location(temp_loc.as_path());
+        if let Some(dir) = full_loc.parent() {
+            fs::create_dir_all(dir).with_context(|| {
+                format!(
+                    "[{}] failed to create directory on {}",
+                    download.subreddit_name,
+                    dir.display()
+                )
+            })?;
+        }
+
+        let mut result = self.try_download(download, temp_loc.as_path(), validator_loc.as_path());
+        for _ in 0..2 {
+            if result.is_ok() || Repository::is_rejected(&result) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(200));
+            result = self.try_download(download, temp_loc.as_path(), validator_loc.as_path());
+        }
+
+        let result = result.and_then(|(bytes, content_type)| {
+            if self.config.downloads.extension == ExtensionSource::Content {
+                if let Some(format) =
+                    Repository::detect_format(temp_loc.as_path(), content_type.as_deref())
+                {
+                    download.set_extension(format);
+                }
+            }
+            let full_loc = download.get_file_location(self.config.get_download_path());
+            fs::rename(temp_loc.as_path(), full_loc.as_path()).with_context(|| {
+                format!(
+                    "[{}] failed moving {} to {}",
+                    download.subreddit_name,
+                    temp_loc.display(),
+                    full_loc.display()
+                )
+            })?;
+            fs::remove_file(validator_loc.as_path()).ok();
+            Ok(bytes)
+        });
+        if Repository::is_rejected(&result) {
+            fs::remove_file(validator_loc.as_path()).ok();
+        }
+        if result.is_err() && !validator_loc.exists() {
+            fs::remove_file(temp_loc.as_path()).ok();
+        }
+        result
+    }
+
+    fn is_rejected<T>(result: &Result<T>) -> bool {
+        match result {
+            Err(err) => err.downcast_ref::<Rejected>().is_some(),
+            Ok(_) => false,
+        }
+    }
+
+    /// Returns the size of the image in bytes, and the response's Content-Type.
+    fn try_download(
+        &self,
+        download: &DownloadMeta,
+        temp_loc: &Path,
+        validator_loc: &Path,
+    ) -> Result<(u64, Option<String>)> {
+        // a partial file can only be resumed if we know which version of the image it belongs to.
+        let validator = fs::read_to_string(validator_loc).ok();
+        let offset = match (validator.as_ref(), fs::metadata(temp_loc)) {
+            (Some(_), Ok(meta)) => meta.len(),
+            _ => 0,
+        };
+
+        let mut request = self.agent.get(download.url.as_str());
+        if let (Some(validator), true) = (validator.as_ref(), offset > 0) {
+            request = request
+                .set("Range", format!("bytes={}-", offset).as_str())
+                .set("If-Range", validator.as_str());
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(416, response)) => {
+                // the partial file already holds the whole image, e.g. when an earlier attempt stopped right before moving it into place
+                let total = Repository::parse_unsatisfied_range(response.header("Content-Range"));
+                if offset > 0 && total == Some(offset) {
+                    info!(
+                        subreddit = %download.subreddit_name,
+                        post_id = %download.post_id,
+                        url = %download.url,
+                        bytes = offset,
+                        "partial download is already complete"
+                    );
+                    self.check_size(Some(offset), download)?;
+                    if self.config.validation.enable {
+                        self.check_file(temp_loc, offset)?;
+                    }
+                    return Ok((offset, None));
+                }
+                // the partial file does not match the image on the server anymore, start over on the next attempt
+                fs::remove_file(validator_loc).ok();
+                fs::remove_file(temp_loc).ok();
+                return Err(Error::msg(format!(
+                    "[{}] server rejected resuming download from {}",
+                    download.subreddit_name, download.url
+                )));
+            }
+            Err(err) => {
+                return Err(Error::new(err)).with_context(|| {
+                    format!(
+                        "[{}] failed to open connection to {}",
+                        download.subreddit_name, download.url
+                    )
+                })
+            }
+        };
+
+        if self.config.validation.enable {
+            Repository::check_response(&response)?;
+        }
+
+        let content_type = response.header("Content-Type").map(|v| v.to_string());
+        let (mut f, expected) = if response.status() == 206 && offset > 0 {
+            info!(
+                subreddit = %download.subreddit_name,
+                post_id = %download.post_id,
+                url = %download.url,
+                offset,
+                "resuming download"
+            );
+            let expected =
+                Repository::parse_content_range(response.header("Content-Range"), offset)
+                    .with_context(|| {
+                        format!(
+                            "[{}] invalid Content-Range when resuming download from {}",
+                            download.subreddit_name, download.url
+                        )
+                    })?;
+            let f = OpenOptions::new()
+                .append(true)
+                .open(temp_loc)
+                .with_context(|| {
+                    format!(
+                        "[{}] failed opening file on {}",
+                        download.subreddit_name,
+                        temp_loc.display()
+                    )
+                })?;
+            (f, Some(expected))
+        } else {
+            // full body. Either a new download, the image has changed, or the server ignores ranges.
+            match Repository::get_validator(&response) {
+                Some(validator) => fs::write(validator_loc, validator).with_context(|| {
+                    format!(
+                        "[{}] failed writing file on {}",
+                        download.subreddit_name,
+                        validator_loc.display()
+                    )
+                })?,
+                None => {
+                    fs::remove_file(validator_loc).ok();
+                }
+            }
+            let expected = match response.header("Content-Encoding") {
+                // Content-Length is the size of the encoded body when the response is compressed
+                None | Some("identity") => response
+                    .header("Content-Length")
+                    .and_then(|v| v.trim().parse::<u64>().ok()),
+                Some(_) => None,
+            };
+            let f = File::create(temp_loc).with_context(|| {
+                format!(
+                    "[{}] failed creating file on {}",
+                    download.subreddit_name,
+                    temp_loc.display()
+                )
+            })?;
+            (f, expected)
+        };
+        self.check_size(expected, download)?;
 
-        let mut buf = response.into_reader();
+        // reads one byte over the limit, to tell a file of exactly the limit from a larger one
+        let limit = match self.config.file_size.maximum_bytes {
+            max if self.config.file_size.enable && max > 0 => (max + 1).saturating_sub(offset),
+            _ => u64::MAX,
+        };
+        let mut buf = response.into_reader().take(limit);
         io::copy(&mut buf, &mut f).with_context(|| {
             format!(
                 "[{}] error when downloading image from {}",
                 download.subreddit_name, download.url
             )
         })?;
+        f.sync_all().with_context(|| {
+            format!(
+                "[{}] failed writing file on {}",
+                download.subreddit_name,
+                temp_loc.display()
+            )
+        })?;
+        let bytes = f.metadata()?.len();
+        self.check_size(Some(bytes), download)?;
+        if let Some(expected) = expected {
+            if bytes != expected {
+                return Err(Error::msg(format!(
+                    "[{}] incomplete download from {}: got {} of {} bytes",
+                    download.subreddit_name, download.url, bytes, expected
+                )));
+            }
+        }
+        if self.config.validation.enable {
+            self.check_file(temp_loc, bytes)?;
+        }
+        Ok((bytes, content_type))
+    }
+
+    /// Rejects images outside the `[file_size]` limits. `size` is the size the server reports, or the size on disk.
+    fn check_size(&self, size: Option<u64>, download: &DownloadMeta) -> Result<()> {
+        let limits = &self.config.file_size;
+        let size = match size {
+            Some(size) if limits.enable => size,
+            _ => return Ok(()),
+        };
+        if limits.maximum_bytes > 0 && size > limits.maximum_bytes {
+            return Err(Error::new(Rejected::new(
+                Filter::FileSize,
+                format!(
+                    "{} is larger than {} bytes",
+                    download.url, limits.maximum_bytes
+                ),
+            )));
+        }
+        if size < limits.minimum_bytes {
+            return Err(Error::new(Rejected::new(
+                Filter::FileSize,
+                format!(
+                    "{} is {} bytes, smaller than {} bytes",
+                    download.url, size, limits.minimum_bytes
+                ),
+            )));
+        }
+        Ok(())
+    }
+
+    /// The file signature is trusted over the Content-Type header.
+    fn detect_format(loc: &Path, content_type: Option<&str>) -> Option<ImageFormat> {
+        let mut magic = [0u8; 16];
+        let n = File::open(loc).and_then(|mut f| f.read(&mut magic)).ok()?;
+        ImageFormat::from_magic(&magic[..n])
+            .or_else(|| content_type.and_then(ImageFormat::from_content_type))
+    }
+
+    /// Rejects responses that are known not to be images before reading the body.
+    fn check_response(response: &Response) -> Result<()> {
+        // imgur redirects removed images to its placeholder
+        if response.get_url().ends_with("/removed.png") {
+            return Err(Error::new(Rejected::new(
+                Filter::Placeholder,
+                format!("redirected to {}", response.get_url()),
+            )));
+        }
+        if let Some(content_type) = response.header("Content-Type") {
+            let mime = content_type.to_lowercase();
+            if !mime.starts_with("image/") && !mime.ends_with("/octet-stream") {
+                return Err(Error::new(Rejected::new(
+                    Filter::NotAnImage,
+                    format!("content type is {}", content_type),
+                )));
+            }
+        }
         Ok(())
     }
 
-    pub fn create_symlink(&self, download: &DownloadMeta) -> Result<()> {
-        if !self.config.symbolic_link.enable {
+    /// Checks the downloaded file's signature, and compares it with the known placeholder images.
+    fn check_file(&self, loc: &Path, size: u64) -> Result<()> {
+        let mut magic = [0u8; 16];
+        let n = File::open(loc)?.read(&mut magic)?;
+        if ImageFormat::from_magic(&magic[..n]).is_none() {
+            return Err(Error::new(Rejected::new(
+                Filter::NotAnImage,
+                "file content is not a jpeg, png, gif or webp image",
+            )));
+        }
+        let candidates = self
+            .config
+            .validation
+            .placeholders
+            .iter()
+            .filter(|p| p.size == size)
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
             return Ok(());
         }
+        let hash = format!("{:x}", Sha256::digest(fs::read(loc)?));
+        if candidates.iter().any(|p| p.matches(size, hash.as_str())) {
+            return Err(Error::new(Rejected::new(
+                Filter::Placeholder,
+                format!("matches a known placeholder of {} bytes", size),
+            )));
+        }
+        Ok(())
+    }
 
-        let download_path = self.config.get_download_path().display().to_string();
+    /// Returns the value to send as `If-Range` when resuming, if the server supports range requests.
+    /// Weak etags can't be used for `If-Range`, so `Last-Modified` is used instead.
+    fn get_validator(response: &Response) -> Option<String> {
+        if response.header("Accept-Ranges") != Some("bytes")
+            || response.header("Content-Encoding").is_some()
+        {
+            return None;
+        }
+        match response.header("ETag") {
+            Some(etag) if !etag.starts_with("W/") => Some(etag.to_string()),
+            _ => response.header("Last-Modified").map(|v| v.to_string()),
+        }
+    }
 
-        let file_path = download.get_file_location(download_path.as_str());
+    /// Returns the total size from `Content-Range: bytes */<total>` of a 416 response.
+    fn parse_unsatisfied_range(content_range: Option<&str>) -> Option<u64> {
+        content_range?
+            .trim()
+            .strip_prefix("bytes */")?
+            .parse::<u64>()
+            .ok()
+    }
 
-        if self.config.symbolic_link.use_custom_path {
-            let custom_path = Path::new(self.config.symbolic_link.custom_path.as_str())
-                .absolutize()?
-                .to_path_buf();
-            fs::create_dir_all(custom_path.as_path())
-                .with_context(|| format!("failed to create folder on {}", custom_path.display()))?;
-            let target = custom_path.join(download.filename.as_str());
-            let src = download.get_file_location(download_path.as_str());
-            symlink::symlink_file(src.clone(), target.clone()).with_context(|| {
-                format!(
-                    "[{}] failed to create symlink from {} to {}",
-                    download.subreddit_name,
-                    src.display(),
-                    target.display()
-                )
-            })?;
+    /// Returns the total size from `Content-Range: bytes <start>-<end>/<total>`.
+    /// Fails if the range does not start at `offset`.
+    fn parse_content_range(content_range: Option<&str>, offset: u64) -> Result<u64> {
+        let content_range = content_range.ok_or_else(|| Error::msg("missing Content-Range"))?;
+        let (range, total) = content_range
+            .trim()
+            .trim_start_matches("bytes ")
+            .split_once('/')
+            .ok_or_else(|| Error::msg(format!("malformed Content-Range '{}'", content_range)))?;
+        let start = range
+            .split('-')
+            .next()
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or_else(|| Error::msg(format!("malformed Content-Range '{}'", content_range)))?;
+        if start != offset {
+            return Err(Error::msg(format!(
+                "expected range to start at {}, got {}",
+                offset, start
+            )));
+        }
+        total.parse::<u64>().map_err(|_| {
+            Error::msg(format!(
+                "unknown total size in Content-Range '{}'",
+                content_range
+            ))
+        })
+    }
+
+    /// `.<filename>.part` in the same directory, so the final rename never crosses file systems.
+    /// Removes partial downloads of failed or cancelled runs that were not resumed for [`STALE_TEMP_AGE`],
+    /// and validators left without their partial file.
+    ///
+    /// Returns the number of removed files.
+    pub fn remove_stale_temp_files(&self) -> Result<usize> {
+        let mut dirs = vec![self.config.get_download_path()];
+        let mut removed = 0;
+        while let Some(dir) = dirs.pop() {
+            let entries = fs::read_dir(dir.as_path())
+                .with_context(|| format!("failed to read directory {}", dir.display()))?;
+            for entry in entries {
+                let entry =
+                    entry.with_context(|| format!("failed to read directory {}", dir.display()))?;
+                let path = entry.path();
+                let name = entry.file_name().to_string_lossy().to_string();
+                let file_type = entry
+                    .file_type()
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                if file_type.is_symlink() {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let stale = if let Some(part) = name.strip_suffix(".validator") {
+                    !path.with_file_name(part).exists()
+                } else if name.starts_with('.') && name.ends_with(".part") {
+                    entry
+                        .metadata()
+                        .ok()
+                        .and_then(|meta| meta.modified().ok())
+                        .and_then(|modified| modified.elapsed().ok())
+                        .map(|age| age > STALE_TEMP_AGE)
+                        .unwrap_or(false)
+                } else {
+                    false
+                };
+                if !stale {
+                    continue;
+                }
+                match fs::remove_file(path.as_path()) {
+                    Ok(_) => {
+                        debug!(path = %path.display(), "removed stale partial download");
+                        removed += 1;
+                    }
+                    Err(err) => warn!(
+                        path = %path.display(),
+                        error = %err,
+                        "failed to remove stale partial download"
+                    ),
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    fn get_temp_location(loc: &Path) -> PathBuf {
+        let filename = loc
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+        loc.with_file_name(format!(".{}.part", filename))
+    }
+
+    /// Holds the `If-Range` value for the partial file. It only exists while the partial file can be resumed.
+    fn get_validator_location(temp_loc: &Path) -> PathBuf {
+        let filename = temp_loc
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+        temp_loc.with_file_name(format!("{}.validator", filename))
+    }
+
+    /// `config` is the config that applies to the source, see [`Config::source_configs`].
+    ///
+    /// Names taken by links to other images are handled with the collision policy.
+    pub fn create_symlink(&self, download: &DownloadMeta, config: &Config) -> Result<()> {
+        if !config.symbolic_link.enable {
             return Ok(());
         }
 
-        let joined_path = Path::new(download_path.as_str()).join("_joined");
-        fs::create_dir_all(joined_path.as_path())?;
-        let target = joined_path.join(download.filename.as_str());
-        symlink::symlink_file(file_path.clone(), target.clone()).with_context(|| {
+        let src = download.get_file_location(self.config.get_download_path());
+        let link_dir = self.get_symlink_dir(config)?;
+        fs::create_dir_all(link_dir.as_path())
+            .with_context(|| format!("failed to create folder on {}", link_dir.display()))?;
+
+        let target = match self.get_symlink_location(download, src.as_path(), link_dir.as_path())? {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        symlink::symlink_file(src.clone(), target.clone()).with_context(|| {
             format!(
                 "[{}] failed to create symlink from {} to {}",
                 download.subreddit_name,
-                file_path.display(),
+                src.display(),
                 target.display()
             )
         })?;
         Ok(())
     }
+
+    /// Removes the links to `src`, e.g. after `src` was replaced by a better copy. The image may have been linked
+    /// by any subreddit or source, so the link folders of all of them are searched.
+    pub fn remove_symlinks(&self, src: &Path) -> Result<()> {
+        let mut link_dirs: Vec<PathBuf> = Vec::new();
+        for (_, config) in self.config.source_configs() {
+            if !config.symbolic_link.enable {
+                continue;
+            }
+            let link_dir = self.get_symlink_dir(&config)?;
+            if !link_dirs.contains(&link_dir) {
+                link_dirs.push(link_dir);
+            }
+        }
+        for link_dir in link_dirs.iter() {
+            let entries = match fs::read_dir(link_dir.as_path()) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let link = entry.path();
+                if fs::read_link(link.as_path()).ok().as_deref() == Some(src) {
+                    fs::remove_file(link.as_path())
+                        .with_context(|| format!("failed to remove symlink {}", link.display()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get_symlink_dir(&self, config: &Config) -> Result<PathBuf> {
+        if config.symbolic_link.use_custom_path {
+            return Ok(Path::new(config.symbolic_link.custom_path.as_str())
+                .absolutize()?
+                .to_path_buf());
+        }
+        Ok(self.config.get_download_path().join("_joined"))
+    }
+
+    /// Picks the name of the link to `src` in `link_dir`. Returns `None` when the link already exists,
+    /// or when the name is taken and the collision policy is `skip`.
+    fn get_symlink_location(
+        &self,
+        download: &DownloadMeta,
+        src: &Path,
+        link_dir: &Path,
+    ) -> Result<Option<PathBuf>> {
+        let policy = self.config.downloads.on_collision;
+        let mut target = link_dir.join(download.filename.as_str());
+        let mut attempt = 0;
+        loop {
+            // symlink_metadata also sees broken links
+            if fs::symlink_metadata(target.as_path()).is_err() {
+                return Ok(Some(target));
+            }
+            if fs::read_link(target.as_path()).ok().as_deref() == Some(src) {
+                return Ok(None);
+            }
+            attempt += 1;
+            match DownloadMeta::collision_filename(
+                download.filename.as_str(),
+                download.post_id.as_str(),
+                policy,
+                attempt,
+            ) {
+                Some(filename) => target = link_dir.join(filename),
+                None if policy == Collision::Overwrite => {
+                    fs::remove_file(target.as_path()).with_context(|| {
+                        format!(
+                            "[{}] failed to remove existing symlink {}",
+                            download.subreddit_name,
+                            target.display()
+                        )
+                    })?;
+                    return Ok(Some(target));
+                }
+                None => {
+                    debug!(
+                        subreddit = %download.subreddit_name,
+                        post_id = %download.post_id,
+                        path = %target.display(),
+                        "symlink skipped, name is taken by another image"
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// How long a 429 response asks to wait, from `Retry-After` or reddit's `X-Ratelimit-Reset`, both in seconds.
+fn rate_limit_wait(response: &Response) -> Duration {
+    ["Retry-After", "X-Ratelimit-Reset"]
+        .iter()
+        .filter_map(|name| response.header(name))
+        .filter_map(|value| value.trim().parse::<f64>().ok())
+        .find(|secs| secs.is_finite() && *secs >= 0.)
+        .map(|secs| Duration::from_secs_f64(secs.min(MAX_RATE_LIMIT_WAIT.as_secs_f64())))
+        .unwrap_or(RATE_LIMIT_WAIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &str) -> Response {
+        format!("HTTP/1.1 429 Too Many Requests\r\n{}\r\n", headers)
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn rate_limit_wait_follows_the_headers() {
+        assert_eq!(
+            rate_limit_wait(&response("Retry-After: 3\r\n")),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            rate_limit_wait(&response("X-Ratelimit-Reset: 1.5\r\n")),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(rate_limit_wait(&response("")), RATE_LIMIT_WAIT);
+        assert_eq!(
+            rate_limit_wait(&response("Retry-After: Wed, 21 Oct 2015 07:28:00 GMT\r\n")),
+            RATE_LIMIT_WAIT
+        );
+    }
+
+    #[test]
+    fn long_rate_limit_waits_are_cut() {
+        for value in ["1e20", "1e300", "18446744073709551616"] {
+            assert_eq!(
+                rate_limit_wait(&response(&format!("Retry-After: {}\r\n", value))),
+                MAX_RATE_LIMIT_WAIT,
+                "{}",
+                value
+            );
+        }
+    }
 }
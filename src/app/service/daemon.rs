@@ -0,0 +1,71 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::app::config::model::Run;
+
+use super::download::DownloadService;
+
+pub struct DaemonService {
+    download: DownloadService,
+    run: Run,
+    json: bool,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl DaemonService {
+    /// `json` prints the report of every run as a log event instead of a table.
+    pub fn new(
+        download: DownloadService,
+        run: Run,
+        json: bool,
+        shutdown: Arc<AtomicBool>,
+    ) -> DaemonService {
+        DaemonService {
+            download,
+            run,
+            json,
+            shutdown,
+        }
+    }
+
+    /// Downloads on every interval until `shutdown` is set.
+    pub fn start(&self) {
+        loop {
+            self.download.start_download().print(self.json);
+            if self.is_shutting_down() {
+                break;
+            }
+            let wait = self.run.interval + fastrand::u64(0..=self.run.jitter);
+            info!(seconds = wait, "waiting for next download");
+            if !self.sleep(Duration::from_secs(wait)) {
+                break;
+            }
+        }
+        info!("stopped");
+    }
+
+    /// Sleeps in short steps, so a shutdown does not wait for the whole interval.
+    /// Returns false if shutdown is requested while sleeping.
+    fn sleep(&self, duration: Duration) -> bool {
+        let step = Duration::from_millis(500);
+        let mut slept = Duration::from_secs(0);
+        while slept < duration {
+            if self.is_shutting_down() {
+                return false;
+            }
+            thread::sleep(step);
+            slept += step;
+        }
+        !self.is_shutting_down()
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,361 @@
+use super::perceptual;
+use crate::app::{
+    config::model::{Config, DedupAction},
+    history::repository::{FileHash, HistoryRepository, ImageHash},
+    reddit::models::meta::{DownloadMeta, MEDIA_EXTENSIONS},
+};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tracing::{debug, info, warn};
+
+/// Finds downloaded images with the same content as an image already saved. Hashes are kept in the history database.
+pub struct DedupService {
+    history: Arc<HistoryRepository>,
+    config: Arc<Config>,
+}
+
+impl DedupService {
+    pub fn new(history: Arc<HistoryRepository>, config: Arc<Config>) -> DedupService {
+        DedupService { history, config }
+    }
+
+    /// Hashes the images in the download path that are new or changed since the last scan.
+    /// Hidden files, symbolic links and the `_joined` folder are left out.
+    ///
+    /// Returns the number of hashed files.
+    pub fn scan(&self) -> Result<usize> {
+        let base = self.config.get_download_path();
+        let mut dirs = vec![base.clone()];
+        let mut hashed = 0;
+        while let Some(dir) = dirs.pop() {
+            let entries = fs::read_dir(dir.as_path())
+                .with_context(|| format!("failed to read directory {}", dir.display()))?;
+            for entry in entries {
+                let entry =
+                    entry.with_context(|| format!("failed to read directory {}", dir.display()))?;
+                let path = entry.path();
+                let name = entry.file_name().to_string_lossy().to_string();
+                let file_type = entry
+                    .file_type()
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                if name.starts_with('.') || file_type.is_symlink() {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    if !(dir == base && name == "_joined") {
+                        dirs.push(path);
+                    }
+                    continue;
+                }
+                if !DedupService::is_image(path.as_path()) {
+                    continue;
+                }
+                match self.scan_file(path.as_path()) {
+                    Ok(true) => hashed += 1,
+                    Ok(false) => {}
+                    Err(err) => warn!(
+                        path = %path.display(),
+                        error = %format!("{:#}", err),
+                        "failed to hash file"
+                    ),
+                }
+            }
+        }
+        if hashed > 0 {
+            info!(files = hashed, "hashed existing images");
+        }
+        Ok(hashed)
+    }
+
+    /// Returns true when the file was hashed.
+    fn scan_file(&self, path: &Path) -> Result<bool> {
+        let (size, modified) = DedupService::stat(path)?;
+        let mut file = FileHash {
+            path: path.to_path_buf(),
+            sha256: String::new(),
+            size,
+            modified,
+        };
+        if self.history.has_file_hash(&file)? {
+            return Ok(false);
+        }
+        file.sha256 = DedupService::hash(path)?;
+        self.history.record_file_hash(&file)?;
+        Ok(true)
+    }
+
+    /// Checks the downloaded image at `loc` against the saved images. A duplicate is replaced as configured in `[dedup]`,
+    /// and the decision is recorded in history.
+    ///
+    /// Returns the first copy when the image is a duplicate.
+    pub fn deduplicate(&self, download: &DownloadMeta, loc: &Path) -> Result<Option<PathBuf>> {
+        let (size, modified) = DedupService::stat(loc)?;
+        let file = FileHash {
+            path: loc.to_path_buf(),
+            sha256: DedupService::hash(loc)?,
+            size,
+            modified,
+        };
+        let original = match self.history.find_or_record_file_hash(&file)? {
+            Some(original) => original,
+            None => return Ok(None),
+        };
+
+        let action = self.config.dedup.action;
+        fs::remove_file(loc).with_context(|| {
+            format!(
+                "[{}] failed to remove duplicate {}",
+                download.subreddit_name,
+                loc.display()
+            )
+        })?;
+        let linked = match action {
+            DedupAction::Hardlink => fs::hard_link(original.as_path(), loc),
+            DedupAction::Symlink => symlink::symlink_file(original.as_path(), loc),
+            DedupAction::Skip => Ok(()),
+        };
+        let action = match linked {
+            Ok(_) => action,
+            Err(err) => {
+                // e.g. hard links across file systems. The image stays reachable through the first copy.
+                warn!(
+                    subreddit = %download.subreddit_name,
+                    post_id = %download.post_id,
+                    path = %loc.display(),
+                    original = %original.display(),
+                    error = %err,
+                    "failed to link duplicate to the first copy, removed it instead"
+                );
+                DedupAction::Skip
+            }
+        };
+        let action = match action {
+            DedupAction::Hardlink => "hardlink",
+            DedupAction::Symlink => "symlink",
+            DedupAction::Skip => "skip",
+        };
+        info!(
+            subreddit = %download.subreddit_name,
+            post_id = %download.post_id,
+            url = %download.url,
+            path = %loc.display(),
+            original = %original.display(),
+            action,
+            "image is a duplicate"
+        );
+        self.history.record_duplicate(
+            download.post_id.as_str(),
+            download.url.as_str(),
+            loc,
+            original.as_path(),
+            action,
+        )?;
+        Ok(Some(original))
+    }
+
+    /// Compares the downloaded image at `loc` with the saved images by perceptual hash. Of similar images,
+    /// the one with the most pixels is kept and the other is removed. The decision is recorded in history.
+    pub fn deduplicate_similar(&self, download: &DownloadMeta, loc: &Path) -> Result<Similarity> {
+        let algorithm = self.config.perceptual_hash.algorithm;
+        let fingerprint = perceptual::fingerprint(loc, algorithm)?;
+        let image = ImageHash {
+            path: loc.to_path_buf(),
+            algorithm: algorithm.as_str().to_string(),
+            hash: fingerprint.hash,
+            width: fingerprint.width,
+            height: fingerprint.height,
+        };
+        let similar = match self
+            .history
+            .find_similar_or_record(&image, self.config.perceptual_hash.threshold)?
+        {
+            Some(similar) => similar,
+            None => return Ok(Similarity::Unique),
+        };
+        let distance = perceptual::hamming_distance(image.hash, similar.hash);
+
+        if image.pixels() <= similar.pixels() {
+            info!(
+                subreddit = %download.subreddit_name,
+                post_id = %download.post_id,
+                url = %download.url,
+                path = %loc.display(),
+                original = %similar.path.display(),
+                distance,
+                "image is similar to a saved image with the same or higher resolution, removing it"
+            );
+            fs::remove_file(loc).with_context(|| {
+                format!(
+                    "[{}] failed to remove similar image {}",
+                    download.subreddit_name,
+                    loc.display()
+                )
+            })?;
+            self.history.record_duplicate(
+                download.post_id.as_str(),
+                download.url.as_str(),
+                loc,
+                similar.path.as_path(),
+                "similar",
+            )?;
+            return Ok(Similarity::Removed);
+        }
+
+        info!(
+            subreddit = %download.subreddit_name,
+            post_id = %download.post_id,
+            url = %download.url,
+            path = %loc.display(),
+            replaced = %similar.path.display(),
+            distance,
+            "image is similar to a saved image with lower resolution, removing the saved image"
+        );
+        fs::remove_file(similar.path.as_path()).with_context(|| {
+            format!(
+                "[{}] failed to remove similar image {}",
+                download.subreddit_name,
+                similar.path.display()
+            )
+        })?;
+        // the replaced image keeps its history entry, so it's not downloaded again
+        let (post_id, url) = self
+            .history
+            .file_owner(similar.path.as_path())?
+            .unwrap_or_default();
+        self.history.record_duplicate(
+            post_id.as_str(),
+            url.as_str(),
+            similar.path.as_path(),
+            loc,
+            "similar",
+        )?;
+        self.relink_duplicates(download, similar.path.as_path(), loc)?;
+        Ok(Similarity::Replaced(similar.path))
+    }
+
+    /// Points the duplicates linked to the `replaced` image at its `replacement`, so they don't dangle.
+    fn relink_duplicates(
+        &self,
+        download: &DownloadMeta,
+        replaced: &Path,
+        replacement: &Path,
+    ) -> Result<()> {
+        for link in self.history.move_linked_duplicates(replaced, replacement)? {
+            // the user may have removed the link or put another file in its place
+            if fs::read_link(link.as_path()).ok().as_deref() != Some(replaced) {
+                continue;
+            }
+            fs::remove_file(link.as_path()).with_context(|| {
+                format!(
+                    "[{}] failed to remove link to replaced image {}",
+                    download.subreddit_name,
+                    link.display()
+                )
+            })?;
+            if let Err(err) = symlink::symlink_file(replacement, link.as_path()) {
+                warn!(
+                    subreddit = %download.subreddit_name,
+                    path = %link.display(),
+                    original = %replacement.display(),
+                    error = %err,
+                    "failed to link duplicate to the replacing image, removed it instead"
+                );
+                continue;
+            }
+            debug!(
+                path = %link.display(),
+                original = %replacement.display(),
+                "duplicate linked to the replacing image"
+            );
+        }
+        Ok(())
+    }
+
+    fn hash(path: &Path) -> Result<String> {
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let hash = format!("{:x}", hasher.finalize());
+        debug!(path = %path.display(), sha256 = %hash, "hashed file");
+        Ok(hash)
+    }
+
+    /// Returns the size and the modification time in seconds.
+    fn stat(path: &Path) -> Result<(i64, i64)> {
+        let meta =
+            fs::metadata(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        Ok((meta.len() as i64, modified))
+    }
+
+    fn is_image(path: &Path) -> bool {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| MEDIA_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+            .unwrap_or(false)
+    }
+}
+
+/// Outcome of comparing a downloaded image by perceptual hash.
+pub enum Similarity {
+    Unique,
+    /// the downloaded image was removed in favor of the saved image with the same or higher resolution.
+    Removed,
+    /// the saved image with lower resolution was removed in favor of the downloaded image.
+    Replaced(PathBuf),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::default::DEFAULT_CONFIG;
+
+    #[test]
+    fn links_to_a_replaced_image_follow_the_replacement() {
+        let path = std::env::temp_dir().join(format!("ridit-relink-{}", std::process::id()));
+        fs::remove_dir_all(path.as_path()).ok();
+        fs::create_dir_all(path.join("a")).unwrap();
+        fs::create_dir_all(path.join("b")).unwrap();
+        let mut config: Config = toml::from_str(DEFAULT_CONFIG).unwrap();
+        config.downloads.path = path.display().to_string();
+        let history = Arc::new(HistoryRepository::new(&config).unwrap());
+        let dedup = DedupService::new(history.clone(), Arc::new(config));
+
+        let replaced = path.join("a").join("small.jpg");
+        let replacement = path.join("a").join("large.jpg");
+        let link = path.join("b").join("copy.jpg");
+        fs::write(replacement.as_path(), b"large").unwrap();
+        symlink::symlink_file(replaced.as_path(), link.as_path()).unwrap();
+        history
+            .record_duplicate("b1", "url", link.as_path(), replaced.as_path(), "symlink")
+            .unwrap();
+
+        dedup
+            .relink_duplicates(
+                &DownloadMeta::default(),
+                replaced.as_path(),
+                replacement.as_path(),
+            )
+            .unwrap();
+
+        assert_eq!(fs::read_link(link.as_path()).unwrap(), replacement);
+        assert_eq!(fs::read(link.as_path()).unwrap(), b"large");
+        assert!(history
+            .move_linked_duplicates(replaced.as_path(), replacement.as_path())
+            .unwrap()
+            .is_empty());
+        fs::remove_dir_all(path.as_path()).ok();
+    }
+}
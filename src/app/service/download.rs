@@ -1,80 +1,529 @@
-use crate::app::{config::model::Config, reddit::repository::Repository};
+use super::dedup::{DedupService, Similarity};
+use super::report::Report;
+use crate::app::{
+    config::model::{Collision, Config, ExtensionSource},
+    history::repository::HistoryRepository,
+    reddit::{
+        models::{
+            filter::{Filter, Rejected},
+            listing::Listing,
+            meta::DownloadMeta,
+        },
+        repository::Repository,
+    },
+};
 use anyhow::Result;
 use rayon::prelude::*;
-use std::sync::{mpsc, Arc};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc,
+};
+use tracing::{debug, error, info, warn};
 
 #[derive(Clone)]
 pub struct DownloadService {
     repo: Arc<Repository>,
+    history: Arc<HistoryRepository>,
+    dedup: Arc<DedupService>,
     config: Arc<Config>,
+    shutdown: Arc<AtomicBool>,
 }
 
 impl DownloadService {
-    pub fn new(repo: Repository, config: Config) -> DownloadService {
+    /// Setting `shutdown` to true stops queueing new downloads. Downloads in progress are still completed.
+    pub fn new(
+        repo: Repository,
+        history: HistoryRepository,
+        config: Config,
+        shutdown: Arc<AtomicBool>,
+    ) -> DownloadService {
+        let history = Arc::new(history);
+        let config = Arc::new(config);
         DownloadService {
             repo: Arc::new(repo),
-            config: Arc::new(config),
+            dedup: Arc::new(DedupService::new(history.clone(), config.clone())),
+            history,
+            config,
+            shutdown,
         }
     }
 
-    pub fn start_download(&self) {
-        let mut a = self
+    pub fn start_download(&self) -> Report {
+        let mut report = Report::default();
+        match self.repo.remove_stale_temp_files() {
+            Ok(0) => {}
+            Ok(removed) => info!(files = removed, "removed stale partial downloads"),
+            Err(err) => {
+                warn!(error = %format!("{:#}", err), "failed to remove stale partial downloads")
+            }
+        }
+        if self.config.dedup.enable && !self.config.history.enable {
+            warn!("dedup needs [history] enabled, images are not deduplicated");
+        }
+        if self.config.perceptual_hash.enable && !self.config.history.enable {
+            warn!("perceptual_hash needs [history] enabled, similar images are not detected");
+        }
+        if self.config.dedup.enable {
+            if let Err(err) = self.dedup.scan() {
+                warn!(error = %format!("{:#}", err), "failed to hash existing images");
+            }
+        }
+        let listings = self
             .config
-            .downloads
-            .subreddits
-            .par_iter()
-            .map(|x| {
-                match self
-                    .repo
-                    .get_listing(x.as_str(), self.config.downloads.sort)
-                {
-                    Ok(v) => Some(v),
-                    Err(err) => {
-                        println!("{:?}", err);
-                        None
-                    }
+            .source_configs()
+            .into_par_iter()
+            .map(|(source, config)| {
+                let name = source.name();
+                let result = self.repo.get_listing(&source, &config);
+                if let Err(err) = result.as_ref() {
+                    error!(source = %name, error = %format!("{:#}", err), "failed to get listing");
                 }
+                (name, result.map(|v| (v, Arc::new(config))))
             })
-            .collect::<Vec<_>>()
-            .into_iter();
+            .collect::<Vec<_>>();
 
         let (tx, rx) = mpsc::channel();
-        while let Some(Some(downloads)) = a.next() {
-            for download in downloads.into_iter() {
-                let zelf = self.clone();
-                let loc = download.get_file_location(zelf.config.get_download_path());
-                if loc.exists() && !zelf.config.downloads.proceed_download_on_file_exist {
+        // locations of the downloads queued in this run, which don't exist on disk yet.
+        let mut claimed = HashSet::new();
+        for (subreddit, result) in listings.into_iter() {
+            report.subreddits += 1;
+            let (collected, config) = match result {
+                Ok(v) => v,
+                Err(_) => {
+                    report.failed_subreddits.push(subreddit);
                     continue;
                 }
+            };
+            report.add_collected(&collected);
+            for download in collected.downloads.into_iter() {
+                if self.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                let zelf = self.clone();
+                let config = config.clone();
+                let mut download = download;
+                match zelf.place(&mut download, &mut claimed) {
+                    Placement::Download => {}
+                    Placement::SkipExisting => {
+                        report.skipped_existing += 1;
+                        continue;
+                    }
+                    Placement::SkipHistory => {
+                        report.skipped_history += 1;
+                        continue;
+                    }
+                    Placement::SkipCollision => {
+                        report.skipped_collision += 1;
+                        continue;
+                    }
+                }
+                let loc = download.get_file_location(zelf.config.get_download_path());
                 let tx = tx.clone();
                 rayon::spawn(move || {
-                    let result = || -> Result<()> {
-                        let response = zelf.repo.download_image(&download)?;
-                        println!(
-                            "[{}] downloading image from: {}",
-                            download.subreddit_name, download.url
+                    let result = || -> Result<Outcome> {
+                        info!(
+                            subreddit = %download.subreddit_name,
+                            post_id = %download.post_id,
+                            url = %download.url,
+                            "downloading image"
                         );
-                        zelf.repo.store_image(response, &download)?;
-                        println!(
-                            "[{}] image downloaded from {} to {}",
-                            download.subreddit_name,
-                            download.url,
-                            loc.display(),
+                        let bytes = match zelf.repo.download_image(&mut download) {
+                            Ok(bytes) => bytes,
+                            Err(err) => match err.downcast_ref::<Rejected>() {
+                                Some(rejected) => {
+                                    info!(
+                                        subreddit = %download.subreddit_name,
+                                        post_id = %download.post_id,
+                                        url = %download.url,
+                                        reason = %rejected,
+                                        "image skipped"
+                                    );
+                                    // recorded so the image is not downloaded again on the next run
+                                    zelf.history.record(&download, loc.as_path())?;
+                                    return Ok(Outcome::Rejected(rejected.filter));
+                                }
+                                None => return Err(err),
+                            },
+                        };
+                        // the extension may have changed to match the image
+                        let loc = download.get_file_location(zelf.config.get_download_path());
+                        info!(
+                            subreddit = %download.subreddit_name,
+                            post_id = %download.post_id,
+                            url = %download.url,
+                            path = %loc.display(),
+                            bytes,
+                            "image downloaded"
                         );
-                        zelf.repo.create_symlink(&download)?;
-                        Ok(())
+                        if config.verify_dimensions.enable {
+                            if let Err(filter) =
+                                zelf.verify_dimensions(&mut download, &loc, &config)
+                            {
+                                // recorded so the image is not downloaded again on the next run
+                                zelf.history.record(&download, loc.as_path())?;
+                                return Ok(Outcome::Rejected(filter));
+                            }
+                        }
+                        zelf.history.record(&download, loc.as_path())?;
+                        if zelf.config.dedup.enable
+                            && zelf.dedup.deduplicate(&download, loc.as_path())?.is_some()
+                        {
+                            return Ok(Outcome::Duplicate(bytes));
+                        }
+                        if zelf.config.perceptual_hash.enable {
+                            match zelf.dedup.deduplicate_similar(&download, loc.as_path()) {
+                                Ok(Similarity::Unique) => {}
+                                Ok(Similarity::Removed) => {
+                                    return Ok(Outcome::Duplicate(bytes));
+                                }
+                                Ok(Similarity::Replaced(replaced)) => {
+                                    zelf.repo.remove_symlinks(replaced.as_path())?;
+                                }
+                                // the image is kept when it can't be decoded
+                                Err(err) => warn!(
+                                    subreddit = %download.subreddit_name,
+                                    post_id = %download.post_id,
+                                    path = %loc.display(),
+                                    error = %format!("{:#}", err),
+                                    "failed to compare image by perceptual hash"
+                                ),
+                            }
+                        }
+                        zelf.repo.create_symlink(&download, &config)?;
+                        Ok(Outcome::Downloaded(bytes))
                     }();
+                    if let Err(err) = result.as_ref() {
+                        error!(
+                            subreddit = %download.subreddit_name,
+                            post_id = %download.post_id,
+                            url = %download.url,
+                            path = %loc.display(),
+                            error = %format!("{:#}", err),
+                            "failed to download image"
+                        );
+                    }
                     tx.send(result).unwrap();
                 })
             }
         }
         drop(tx);
-        loop {
-            match rx.recv() {
-                Ok(Err(err)) => println!("{:?}", err),
-                Ok(_) => {}
-                Err(_) => break,
+        // wait for every download to finish
+        for result in rx.iter() {
+            match result {
+                Ok(Outcome::Downloaded(bytes)) => {
+                    report.downloaded += 1;
+                    report.bytes += bytes;
+                }
+                Ok(Outcome::Duplicate(bytes)) => {
+                    report.duplicates += 1;
+                    report.bytes += bytes;
+                }
+                Ok(Outcome::Rejected(filter)) => {
+                    *report.filtered.entry(filter).or_default() += 1;
+                }
+                Err(_) => report.failed += 1,
+            }
+        }
+        report
+    }
+
+    /// Decides whether the image is downloaded. When the file name is taken by the image of another post,
+    /// `download.filename` is changed according to the collision policy.
+    ///
+    /// With extensions from the content, the name is checked with every image extension, since the extension
+    /// may change after download. So the file can't end up on the file of another post.
+    fn place(&self, download: &mut DownloadMeta, claimed: &mut HashSet<PathBuf>) -> Placement {
+        let proceed = self.config.downloads.proceed_download_on_file_exist;
+        let policy = self.config.downloads.on_collision;
+        let mut owners = self.owners(download, claimed);
+        if !proceed {
+            if owners.this {
+                return Placement::SkipExisting;
+            }
+            if self.is_in_history(download) {
+                return Placement::SkipHistory;
+            }
+        }
+
+        let filename = download.filename.clone();
+        let mut attempt = 0;
+        while owners.other {
+            attempt += 1;
+            download.filename = match DownloadMeta::collision_filename(
+                filename.as_str(),
+                download.post_id.as_str(),
+                policy,
+                attempt,
+            ) {
+                Some(filename) => filename,
+                None => break,
+            };
+            owners = self.owners(download, claimed);
+            if owners.this && !proceed {
+                return Placement::SkipExisting;
+            }
+        }
+        if owners.other && policy == Collision::Skip {
+            info!(
+                subreddit = %download.subreddit_name,
+                post_id = %download.post_id,
+                url = %download.url,
+                filename = %download.filename,
+                "image skipped, file name is taken by another post"
+            );
+            return Placement::SkipCollision;
+        }
+        if attempt > 0 {
+            debug!(
+                subreddit = %download.subreddit_name,
+                post_id = %download.post_id,
+                from = %filename,
+                to = %download.filename,
+                "file name is taken by another post, renamed"
+            );
+        }
+        claimed.insert(self.claim_key(download));
+        Placement::Download
+    }
+
+    /// Tells who the files on the download's location belong to. Files not in history are assumed to be of this download,
+    /// like before history existed.
+    fn owners(&self, download: &DownloadMeta, claimed: &HashSet<PathBuf>) -> Owners {
+        let mut owners = Owners {
+            this: false,
+            other: claimed.contains(&self.claim_key(download)),
+        };
+        let any_extension = self.config.downloads.extension == ExtensionSource::Content;
+        for existing in download
+            .get_existing_file_locations(self.config.get_download_path(), any_extension)
+            .into_iter()
+        {
+            match self.history.file_owner(existing.as_path()) {
+                Ok(Some((post_id, url))) if post_id != download.post_id || url != download.url => {
+                    owners.other = true
+                }
+                Ok(_) => owners.this = true,
+                Err(err) => {
+                    warn!(
+                        path = %existing.display(),
+                        error = %format!("{:#}", err),
+                        "failed to check download history"
+                    );
+                    owners.this = true
+                }
             }
         }
+        owners
+    }
+
+    /// Downloads queued in this run are told apart by this key, since their files don't exist yet.
+    fn claim_key(&self, download: &DownloadMeta) -> PathBuf {
+        let base = self.config.get_download_path();
+        if self.config.downloads.extension == ExtensionSource::Content {
+            download.get_file_stem_location(base)
+        } else {
+            download.get_file_location(base)
+        }
+    }
+
+    /// Reads the real dimensions from the downloaded file and checks them against the filters.
+    /// Files that fail are deleted.
+    fn verify_dimensions(
+        &self,
+        download: &mut DownloadMeta,
+        loc: &Path,
+        config: &Config,
+    ) -> Result<(), Filter> {
+        let result = match imagesize::size(loc) {
+            Ok(size) => {
+                let image_size = (size.width as u32, size.height as u32);
+                download.image_size = Some(image_size);
+                Listing::check_image_size(image_size, config)
+            }
+            Err(_) => Err(Filter::UnreadableImage),
+        };
+        if let Err(filter) = result {
+            info!(
+                subreddit = %download.subreddit_name,
+                post_id = %download.post_id,
+                url = %download.url,
+                path = %loc.display(),
+                size = ?download.image_size,
+                reason = %filter,
+                "removing image that failed dimension check"
+            );
+            if let Err(err) = fs::remove_file(loc) {
+                warn!(
+                    path = %loc.display(),
+                    error = %err,
+                    "failed to remove image"
+                );
+            }
+        }
+        result
+    }
+
+    fn is_in_history(&self, download: &DownloadMeta) -> bool {
+        match self.history.contains(download) {
+            Ok(found) => found,
+            Err(err) => {
+                warn!(
+                    subreddit = %download.subreddit_name,
+                    post_id = %download.post_id,
+                    url = %download.url,
+                    error = %format!("{:#}", err),
+                    "failed to check download history"
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Whether an image is queued for download.
+enum Placement {
+    Download,
+    SkipExisting,
+    SkipHistory,
+    /// the file name is taken by another post and the collision policy is `skip`.
+    SkipCollision,
+}
+
+/// Who the existing or queued files on a download's location belong to.
+struct Owners {
+    this: bool,
+    other: bool,
+}
+
+/// Result of a single download task.
+enum Outcome {
+    Downloaded(u64),
+    /// the image has the same content as an image already saved.
+    Duplicate(u64),
+    /// removed after download because the real image failed a filter.
+    Rejected(Filter),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::default::DEFAULT_CONFIG;
+    use crate::app::reddit::agent::new_agent;
+
+    /// A service on an empty download path in the temp dir, removed when dropped.
+    struct TestService {
+        service: DownloadService,
+        path: PathBuf,
+    }
+
+    impl Drop for TestService {
+        fn drop(&mut self) {
+            fs::remove_dir_all(self.path.as_path()).ok();
+        }
+    }
+
+    fn service(name: &str, extension: ExtensionSource) -> TestService {
+        let path = std::env::temp_dir().join(format!("ridit-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(path.as_path()).ok();
+        fs::create_dir_all(path.as_path()).unwrap();
+        let mut config: Config = toml::from_str(DEFAULT_CONFIG).unwrap();
+        config.downloads.path = path.display().to_string();
+        config.downloads.extension = extension;
+        config.downloads.on_collision = Collision::Suffix;
+        config.symbolic_link.enable = false;
+        let repo = Repository::new(new_agent(&config), None, config.clone());
+        let history = HistoryRepository::new(&config).unwrap();
+        let service = DownloadService::new(repo, history, config, Arc::new(AtomicBool::new(false)));
+        TestService { service, path }
+    }
+
+    fn download(post_id: &str, filename: &str) -> DownloadMeta {
+        DownloadMeta {
+            post_id: post_id.to_string(),
+            url: format!("https://i.redd.it/{}", filename),
+            subreddit_name: "wallpaper".to_string(),
+            directory: "wallpaper".to_string(),
+            filename: filename.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn queued_name_is_claimed_with_every_extension() {
+        let test = service("claimed", ExtensionSource::Content);
+        let mut claimed = HashSet::new();
+        let mut first = download("a", "image.jpg");
+        let mut second = download("b", "image.png");
+
+        assert!(matches!(
+            test.service.place(&mut first, &mut claimed),
+            Placement::Download
+        ));
+        // the first image may turn out to be a png, so the second can't take image.png
+        assert!(matches!(
+            test.service.place(&mut second, &mut claimed),
+            Placement::Download
+        ));
+        assert_eq!(first.filename, "image.jpg");
+        assert_eq!(second.filename, "image_1.png");
+    }
+
+    #[test]
+    fn existing_file_of_another_post_with_another_extension_is_kept() {
+        let test = service("existing", ExtensionSource::Content);
+        let existing = download("a", "image.png");
+        let loc = existing.get_file_location(test.path.as_path());
+        fs::create_dir_all(loc.parent().unwrap()).unwrap();
+        fs::write(loc.as_path(), b"png").unwrap();
+        test.service
+            .history
+            .record(&existing, loc.as_path())
+            .unwrap();
+
+        let mut claimed = HashSet::new();
+        let mut new = download("b", "image.jpg");
+        assert!(matches!(
+            test.service.place(&mut new, &mut claimed),
+            Placement::Download
+        ));
+        assert_eq!(new.filename, "image_1.jpg");
+    }
+
+    #[test]
+    fn existing_file_of_this_post_with_another_extension_is_skipped() {
+        let test = service("own", ExtensionSource::Content);
+        let existing = download("a", "image.png");
+        let loc = existing.get_file_location(test.path.as_path());
+        fs::create_dir_all(loc.parent().unwrap()).unwrap();
+        fs::write(loc.as_path(), b"png").unwrap();
+        test.service
+            .history
+            .record(&existing, loc.as_path())
+            .unwrap();
+
+        let mut claimed = HashSet::new();
+        let mut again = DownloadMeta {
+            filename: "image.jpg".to_string(),
+            ..existing
+        };
+        assert!(matches!(
+            test.service.place(&mut again, &mut claimed),
+            Placement::SkipExisting
+        ));
+    }
+
+    #[test]
+    fn extensions_from_the_url_are_claimed_as_is() {
+        let test = service("url", ExtensionSource::Url);
+        let mut claimed = HashSet::new();
+        let mut first = download("a", "image.jpg");
+        let mut second = download("b", "image.png");
+
+        test.service.place(&mut first, &mut claimed);
+        test.service.place(&mut second, &mut claimed);
+        assert_eq!(second.filename, "image.png");
     }
 }
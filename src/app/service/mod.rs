@@ -1 +1,5 @@
+pub mod daemon;
+pub mod dedup;
 pub mod download;
+pub mod perceptual;
+pub mod report;
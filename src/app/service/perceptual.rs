@@ -0,0 +1,96 @@
+use crate::app::config::model::HashAlgorithm;
+use anyhow::{Context, Result};
+use image::{imageops::FilterType, GrayImage, ImageReader};
+use std::path::Path;
+
+/// Perceptual hash of an image with the size of the decoded image.
+pub struct Fingerprint {
+    pub hash: u64,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decodes the image and computes its 64 bit perceptual hash. Similar images have hashes with a small Hamming distance,
+/// even after resizing or re-encoding.
+pub fn fingerprint(path: &Path, algorithm: HashAlgorithm) -> Result<Fingerprint> {
+    let img = ImageReader::open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?
+        .with_guessed_format()
+        .with_context(|| format!("failed to read {}", path.display()))?
+        .decode()
+        .with_context(|| format!("failed to decode {}", path.display()))?;
+    let gray = img.to_luma8();
+    let hash = match algorithm {
+        HashAlgorithm::Ahash => average_hash(&gray),
+        HashAlgorithm::Dhash => difference_hash(&gray),
+        HashAlgorithm::Phash => dct_hash(&gray),
+    };
+    Ok(Fingerprint {
+        hash,
+        width: img.width(),
+        height: img.height(),
+    })
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Sets a bit for every pixel of the 8x8 thumbnail brighter than the thumbnail's mean.
+fn average_hash(gray: &GrayImage) -> u64 {
+    let small = image::imageops::resize(gray, 8, 8, FilterType::Triangle);
+    let pixels = small.pixels().map(|p| p[0] as u32).collect::<Vec<_>>();
+    let mean = pixels.iter().sum::<u32>() / pixels.len() as u32;
+    to_bits(pixels.iter().map(|&p| p > mean))
+}
+
+/// Sets a bit for every pixel of the 9x8 thumbnail brighter than its right neighbour.
+fn difference_hash(gray: &GrayImage) -> u64 {
+    let small = image::imageops::resize(gray, 9, 8, FilterType::Triangle);
+    let bits = (0..8).flat_map(|y| {
+        let small = &small;
+        (0..8).map(move |x| small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0])
+    });
+    to_bits(bits)
+}
+
+/// Sets a bit for every low frequency of the 32x32 thumbnail's DCT above the median. The DC term is left out of the median.
+fn dct_hash(gray: &GrayImage) -> u64 {
+    const SIZE: usize = 32;
+    let small = image::imageops::resize(gray, SIZE as u32, SIZE as u32, FilterType::Triangle);
+    let pixels = small.pixels().map(|p| p[0] as f64).collect::<Vec<_>>();
+
+    let cos = (0..8)
+        .map(|u| {
+            (0..SIZE)
+                .map(|x| {
+                    (((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI) / (2 * SIZE) as f64)
+                        .cos()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    // rows first, then columns. Only the 8x8 low frequencies are needed.
+    let rows = (0..SIZE)
+        .flat_map(|y| {
+            let pixels = &pixels;
+            cos.iter()
+                .map(move |c| (0..SIZE).map(|x| pixels[y * SIZE + x] * c[x]).sum::<f64>())
+        })
+        .collect::<Vec<_>>();
+    let freqs = (0..8)
+        .flat_map(|v| {
+            let (rows, cos) = (&rows, &cos);
+            (0..8).map(move |u| (0..SIZE).map(|y| rows[y * 8 + u] * cos[v][y]).sum::<f64>())
+        })
+        .collect::<Vec<_>>();
+
+    let mut sorted = freqs[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+    to_bits(freqs.iter().map(|&f| f > median))
+}
+
+fn to_bits<I: Iterator<Item = bool>>(bits: I) -> u64 {
+    bits.fold(0u64, |hash, bit| (hash << 1) | bit as u64)
+}
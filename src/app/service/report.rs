@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use tracing::info;
+
+use crate::app::reddit::models::filter::{Collected, Filter};
+
+/// What happened in a single download run.
+#[derive(Default, Debug)]
+pub struct Report {
+    pub subreddits: usize,
+    pub failed_subreddits: Vec<String>,
+    pub listings: usize,
+    pub failed_pages: usize,
+    pub posts: usize,
+    pub filtered: BTreeMap<Filter, usize>,
+    pub skipped_existing: usize,
+    pub skipped_history: usize,
+    pub skipped_collision: usize,
+    pub downloaded: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+    pub bytes: u64,
+}
+
+impl Report {
+    pub fn add_collected(&mut self, collected: &Collected) {
+        self.listings += collected.listings;
+        self.failed_pages += collected.failed_pages;
+        self.posts += collected.posts;
+        for (filter, count) in collected.filtered.iter() {
+            *self.filtered.entry(*filter).or_default() += count;
+        }
+    }
+
+    /// A run fails when any subreddit listing, listing page or download failed.
+    pub fn is_success(&self) -> bool {
+        self.failed_subreddits.is_empty() && self.failed_pages == 0 && self.failed == 0
+    }
+
+    /// Prints the summary table, or logs it as a single event when logs are consumed by machines.
+    pub fn print(&self, json: bool) {
+        if !json {
+            println!("{}", self);
+            return;
+        }
+        let filtered = self
+            .filtered
+            .iter()
+            .map(|(filter, count)| format!("{}={}", filter, count))
+            .collect::<Vec<_>>()
+            .join(",");
+        info!(
+            subreddits = self.subreddits,
+            failed_subreddits = %self.failed_subreddits.join(","),
+            listings = self.listings,
+            failed_pages = self.failed_pages,
+            posts = self.posts,
+            filtered = %filtered,
+            skipped_existing = self.skipped_existing,
+            skipped_history = self.skipped_history,
+            skipped_collision = self.skipped_collision,
+            downloaded = self.downloaded,
+            duplicates = self.duplicates,
+            failed = self.failed,
+            bytes = self.bytes,
+            "download run finished"
+        );
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "\nSummary")?;
+        writeln!(f, "  {:<28}{:>10}", "subreddits", self.subreddits)?;
+        if !self.failed_subreddits.is_empty() {
+            writeln!(
+                f,
+                "  {:<28}{:>10}  ({})",
+                "failed subreddits",
+                self.failed_subreddits.len(),
+                self.failed_subreddits.join(", ")
+            )?;
+        }
+        writeln!(f, "  {:<28}{:>10}", "listings fetched", self.listings)?;
+        if self.failed_pages > 0 {
+            writeln!(
+                f,
+                "  {:<28}{:>10}",
+                "failed listing pages", self.failed_pages
+            )?;
+        }
+        writeln!(f, "  {:<28}{:>10}", "posts seen", self.posts)?;
+        for (filter, count) in self.filtered.iter() {
+            writeln!(
+                f,
+                "  {:<28}{:>10}",
+                format!("filtered by {}", filter),
+                count
+            )?;
+        }
+        writeln!(
+            f,
+            "  {:<28}{:>10}",
+            "skipped, file exists", self.skipped_existing
+        )?;
+        writeln!(
+            f,
+            "  {:<28}{:>10}",
+            "skipped, in history", self.skipped_history
+        )?;
+        writeln!(
+            f,
+            "  {:<28}{:>10}",
+            "skipped, name taken", self.skipped_collision
+        )?;
+        writeln!(f, "  {:<28}{:>10}", "downloaded", self.downloaded)?;
+        if self.duplicates > 0 {
+            writeln!(f, "  {:<28}{:>10}", "duplicates", self.duplicates)?;
+        }
+        writeln!(f, "  {:<28}{:>10}", "failed", self.failed)?;
+        write!(f, "  {:<28}{:>10}", "bytes written", self.bytes)
+    }
+}
@@ -1,39 +1,175 @@
 mod app;
-use anyhow::Result;
+use anyhow::{Context, Error, Result};
 use app::{
+    cli::{Cli, Command, ConfigCommand},
     config::{self, model::Config, thread::configure_concurrency},
-    reddit::{agent::new_agent, repository::Repository},
-    service::download::DownloadService,
+    history::repository::HistoryRepository,
+    logger,
+    reddit::{
+        agent::{new_agent, new_oauth},
+        repository::Repository,
+    },
+    service::{daemon::DaemonService, download::DownloadService},
 };
+use clap::Parser;
 use std::io::Read;
 use std::io::Write;
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tracing::warn;
 
 pub fn execute() {
-    match print_config() {
-        Ok(new) => {
-            if new {
-                println!("configure and rerun the executable");
-                exit(0)
+    let cli = Cli::parse();
+    let result = match cli.command.clone().unwrap_or(Command::Download) {
+        Command::Download => download(&cli, false),
+        Command::Watch => download(&cli, true),
+        Command::Init { force } => init(&cli, force),
+        Command::Config {
+            command: ConfigCommand::Show,
+        } => show_config(&cli),
+        Command::Config {
+            command: ConfigCommand::Path,
+        } => show_config_path(&cli),
+        Command::Validate => validate(&cli),
+    };
+    if let Err(err) = result {
+        println!("{:#}", err);
+        exit(1);
+    }
+}
+
+fn download(cli: &Cli, watch: bool) -> Result<()> {
+    // Without an explicit config file, the first run generates one for the user to edit.
+    if cli.config.is_none() && print_config().context("failed to create config")? {
+        println!("configure and rerun the executable");
+        return Ok(());
+    }
+    let c = load_config(cli)?;
+    logger::init(&c.log)?;
+    let daemon = watch || c.run.daemon;
+    let hold = c.run.hold_on_job_done && !daemon;
+    c.create_dirs()?;
+    configure_concurrency(0)?;
+    let agent = new_agent(&c);
+    let shutdown = handle_shutdown()?;
+
+    let oauth = new_oauth(&agent, &c);
+    let repo = Repository::new(agent, oauth, c.clone());
+    let history = HistoryRepository::new(&c)?;
+    let run = c.run.clone();
+    let json = c.log.is_json();
+    let service = DownloadService::new(repo, history, c, shutdown.clone());
+
+    if daemon {
+        DaemonService::new(service, run, json, shutdown).start();
+        return Ok(());
+    }
+
+    let report = service.start_download();
+    report.print(json);
+    pause(hold);
+    if !report.is_success() {
+        return Err(Error::msg(format!(
+            "{} subreddit(s) and {} download(s) failed",
+            report.failed_subreddits.len(),
+            report.failed
+        )));
+    }
+    Ok(())
+}
+
+/// The first SIGINT/SIGTERM lets downloads in progress finish. The second one exits immediately.
+fn handle_shutdown() -> Result<Arc<AtomicBool>> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let flag = shutdown.clone();
+    ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::SeqCst) {
+            exit(130);
+        }
+        warn!(
+            "shutting down after downloads in progress are done. press Ctrl+C again to force quit"
+        );
+    })
+    .context("failed to set signal handler")?;
+    Ok(shutdown)
+}
+
+fn init(cli: &Cli, force: bool) -> Result<()> {
+    let (rel, xdg) = Config::check_config_exists();
+    let target = match cli.config.as_ref() {
+        Some(p) => Some(p.clone()),
+        None if rel || xdg => Some(config_path(cli)?),
+        None => None,
+    };
+    match target {
+        Some(p) => {
+            if p.exists() && !force {
+                return Err(Error::msg(format!(
+                    "config already exists on {}. use --force to overwrite it",
+                    p.display()
+                )));
             }
+            Config::print_config_to(p.as_path())?;
+            println!("config created on {}", p.display());
         }
-        Err(err) => {
-            println!("failed to create config: {}", err);
-            exit(1);
+        None => {
+            let p = Config::print_config()?;
+            println!("config created on {}", p.display());
         }
     }
-    let c = config::read_config().unwrap();
-    let hold = c.run.hold_on_job_done;
-    c.create_dirs().unwrap();
-    configure_concurrency(0).unwrap();
-    let agent = new_agent(&c);
+    Ok(())
+}
 
-    let repo = Repository::new(agent, c.clone());
-    let service = DownloadService::new(repo, c);
+fn show_config(cli: &Cli) -> Result<()> {
+    let mut c = load_config(cli)?;
+    // the output ends up in terminals and bug reports
+    for secret in [
+        &mut c.advanced.reddit_client_secret,
+        &mut c.advanced.reddit_password,
+    ] {
+        if !secret.is_empty() {
+            *secret = "********".to_string();
+        }
+    }
+    // converting to a value first lets toml order plain values before tables.
+    let s = toml::Value::try_from(&c)
+        .and_then(|v| toml::to_string(&v))
+        .context("failed to serialize config")?;
+    println!("{}", s);
+    Ok(())
+}
 
-    service.start_download();
+fn show_config_path(cli: &Cli) -> Result<()> {
+    println!("{}", config_path(cli)?.display());
+    Ok(())
+}
 
-    pause(hold);
+fn validate(cli: &Cli) -> Result<()> {
+    let p = config_path(cli)?;
+    let c = load_config(cli)?;
+    c.validate()
+        .with_context(|| format!("invalid config on {}", p.display()))?;
+    println!("config on {} is valid", p.display());
+    Ok(())
+}
+
+fn config_path(cli: &Cli) -> Result<PathBuf> {
+    match cli.config.as_ref() {
+        Some(p) => Ok(p.clone()),
+        None => config::get_config_path().map_err(|err| Error::msg(err.to_string())),
+    }
+}
+
+/// Reads the config and applies the command line overrides.
+fn load_config(cli: &Cli) -> Result<Config> {
+    let p = config_path(cli)?;
+    let mut c = config::read_config_from(p.as_path()).map_err(|err| Error::msg(err.to_string()))?;
+    cli.overrides.apply(&mut c);
+    Ok(c)
 }
 
 fn print_config() -> Result<bool> {
@@ -1,5 +1,3 @@
-use ridit;
-
 fn main() {
     ridit::execute();
 }